@@ -0,0 +1,62 @@
+//! Fan-out of a single trade, encoding into a fresh stack buffer per recipient (the old
+//! encoder) versus encoding once into a reused `EncodeBuffer`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use single_thread_async_server::{
+    encoder::EncodeBuffer,
+    models::{Encode, Product, Trade},
+};
+
+const RECIPIENTS: usize = 64;
+
+fn fresh_buffers(trade: &Trade, pending: &mut [Vec<u8>]) {
+    for out in pending {
+        let mut buffer = [0; 1024];
+        let length = trade.encode(&mut buffer).unwrap();
+        out.extend_from_slice(&buffer[..length]);
+    }
+}
+
+fn reused_buffer(buffer: &mut EncodeBuffer, trade: &Trade, pending: &mut [Vec<u8>]) {
+    let frame = buffer.encode(trade).unwrap();
+    for out in pending {
+        out.extend_from_slice(frame);
+    }
+}
+
+fn bench_fan_out(c: &mut Criterion) {
+    let trade = Trade {
+        product: Product::Apples,
+        venue: None,
+    };
+    let mut buffer = EncodeBuffer::default();
+
+    let mut expected = vec![Vec::new(); RECIPIENTS];
+    let mut actual = vec![Vec::new(); RECIPIENTS];
+    fresh_buffers(&trade, &mut expected);
+    reused_buffer(&mut buffer, &trade, &mut actual);
+    assert_eq!(
+        expected, actual,
+        "Both strategies must produce identical frames"
+    );
+
+    let mut group = c.benchmark_group("trade_fan_out");
+    group.bench_function("fresh_buffers", |b| {
+        let mut pending = vec![Vec::new(); RECIPIENTS];
+        b.iter(|| {
+            pending.iter_mut().for_each(Vec::clear);
+            fresh_buffers(black_box(&trade), &mut pending);
+        });
+    });
+    group.bench_function("reused_buffer", |b| {
+        let mut pending = vec![Vec::new(); RECIPIENTS];
+        b.iter(|| {
+            pending.iter_mut().for_each(Vec::clear);
+            reused_buffer(&mut buffer, black_box(&trade), &mut pending);
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_fan_out);
+criterion_main!(benches);
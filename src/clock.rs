@@ -0,0 +1,122 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{sync::Notify, time::Instant};
+
+/// Source of time for everything that expires, times out or reports timestamps.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+}
+
+/// Real time through `tokio::time`, so it also honours `tokio::time::pause`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Clock that only moves when `advance` is called. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    inner: Arc<MockClockInner>,
+}
+
+#[derive(Debug)]
+struct MockClockInner {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Notify,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(MockClockInner {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+                advanced: Notify::new(),
+            }),
+        }
+    }
+
+    /// Moves time forward, waking every sleep whose deadline has passed.
+    pub fn advance(&self, duration: Duration) {
+        *self.lock_elapsed() += duration;
+        self.inner.advanced.notify_waiters();
+    }
+
+    fn lock_elapsed(&self) -> std::sync::MutexGuard<'_, Duration> {
+        self.inner
+            .elapsed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.inner.start + *self.lock_elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let clock = self.clone();
+        let deadline = self.now() + duration;
+        Box::pin(async move {
+            loop {
+                // Register before checking so an `advance` in between is not missed
+                let advanced = clock.inner.advanced.notified();
+                if clock.now() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_clock_sleep_wakes_on_advance() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+
+        clock.advance(Duration::from_secs(5));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        tokio::time::timeout(Duration::from_secs(1), sleep)
+            .await
+            .expect("Sleep did not wake")
+            .expect("Sleep panicked");
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+    }
+}
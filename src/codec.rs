@@ -0,0 +1,334 @@
+use std::io::Write;
+
+use bytes::{Buf, BytesMut};
+
+use crate::models::{ClientOption, Encode, ProductCase, Request};
+
+/// Starts the trailer of lines sent with `ClientOption::Crc`, followed by the CRC32 of the rest
+/// of the line as 8 hex digits: `ACK:APPLE|crc=1a2b3c4d`.
+pub const CRC_TRAILER: &[u8] = b"|crc=";
+
+/// A line from a client that enabled `ClientOption::Crc` had no trailer, or a wrong one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcMismatch;
+
+impl std::fmt::Display for CrcMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CRC trailer missing or wrong")
+    }
+}
+
+impl std::error::Error for CrcMismatch {}
+
+/// CRC32 (IEEE) of `bytes`.
+#[must_use]
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// Appends `frames` to `buffer`, with a CRC trailer before the newline of every line.
+pub fn append_with_crc(frames: &[u8], buffer: &mut Vec<u8>) {
+    for line in frames.split_inclusive(|&b| b == b'\n') {
+        let (content, newline) = line
+            .strip_suffix(b"\n")
+            .map_or((line, false), |content| (content, true));
+        buffer.extend_from_slice(content);
+        buffer.extend_from_slice(CRC_TRAILER);
+        // Writing to a Vec cannot fail
+        let _ = write!(buffer, "{:08x}", crc32(content));
+        if newline {
+            buffer.push(b'\n');
+        }
+    }
+}
+
+/// Checks the CRC trailer of `line` and returns the line without it.
+fn strip_crc(line: &[u8]) -> Result<&[u8], CrcMismatch> {
+    let start = line
+        .windows(CRC_TRAILER.len())
+        .rposition(|window| window == CRC_TRAILER)
+        .ok_or(CrcMismatch)?;
+    let crc = std::str::from_utf8(&line[start + CRC_TRAILER.len()..])
+        .ok()
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or(CrcMismatch)?;
+    let content = &line[..start];
+    if crc32(content) == crc {
+        Ok(content)
+    } else {
+        Err(CrcMismatch)
+    }
+}
+
+/// Wire format spoken with clients: how requests are cut out of the received bytes and
+/// parsed, and how frames are written.
+pub trait Codec: std::fmt::Debug + Send + Sync {
+    /// Takes the next request off the front of `buffer`, `Ok(None)` if more bytes are needed.
+    /// A malformed request is consumed and reported as an error, later requests can still be
+    /// decoded.
+    fn decode(&mut self, buffer: &mut BytesMut) -> anyhow::Result<Option<Request>>;
+
+    /// Like `decode`, for the bytes left once the client closed its side of the connection.
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> anyhow::Result<Option<Request>> {
+        self.decode(buffer)
+    }
+
+    /// Appends `message` to `buffer`.
+    fn encode<T: Encode + ?Sized>(
+        &mut self,
+        message: &T,
+        buffer: &mut BytesMut,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized;
+}
+
+/// Newline delimited text frames, e.g. `BUY:APPLE\n`. Lines may also end in `\r\n`.
+///
+/// Once `OPTS:crc` is decoded, every later line must carry a CRC trailer. Lines without a
+/// matching one are reported as `CrcMismatch`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TextCodec {
+    product_case: ProductCase,
+    crc: bool,
+}
+
+impl TextCodec {
+    #[must_use]
+    pub const fn new(product_case: ProductCase) -> Self {
+        Self {
+            product_case,
+            crc: false,
+        }
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> anyhow::Result<Request> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        if self.crc {
+            line = strip_crc(line)?;
+        }
+        let request = Request::parse_with_case(std::str::from_utf8(line)?, self.product_case)?;
+        if matches!(request, Request::Options(ClientOption::Crc)) {
+            self.crc = true;
+        }
+
+        Ok(request)
+    }
+}
+
+impl Codec for TextCodec {
+    fn decode(&mut self, buffer: &mut BytesMut) -> anyhow::Result<Option<Request>> {
+        let Some(end) = buffer.iter().position(|&b| b == b'\n') else {
+            return Ok(None);
+        };
+        let line = buffer.split_to(end);
+        buffer.advance(1);
+
+        self.parse_line(&line).map(Some)
+    }
+
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> anyhow::Result<Option<Request>> {
+        if let Some(request) = self.decode(buffer)? {
+            return Ok(Some(request));
+        }
+        // A last line without a newline still counts
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        let line = buffer.split();
+
+        self.parse_line(&line).map(Some)
+    }
+
+    fn encode<T: Encode + ?Sized>(
+        &mut self,
+        message: &T,
+        buffer: &mut BytesMut,
+    ) -> anyhow::Result<()> {
+        let start = buffer.len();
+        buffer.resize(start + message.encoded_len(), 0);
+        let length = message.encode(&mut buffer[start..])?;
+        buffer.truncate(start + length);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use crate::models::{
+        ClientId, ClientOption, Login, Message, Order, Product, Side, Subscription,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_text_codec_decodes_lines() {
+        let mut codec = TextCodec::default();
+        let mut buffer = BytesMut::from(&b"INFO\r\nBUY:APPLE@clid=1\nSUB:PE"[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Info)
+        ));
+        let Some(Request::Order(order)) = codec.decode(&mut buffer).unwrap() else {
+            panic!("Expected an order");
+        };
+        assert_eq!(order.client_order_id.as_deref(), Some("1"));
+
+        // Waits for the rest of the line
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(b"AR\n");
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Subscribe(
+                Subscription::Product(Product::Pears),
+                false
+            ))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_text_codec_skips_malformed_lines() {
+        let mut codec = TextCodec::default();
+        let mut buffer = BytesMut::from(&b"BUY:apple\n\xff\nINFO"[..]);
+
+        assert!(codec.decode(&mut buffer).is_err());
+        assert!(codec.decode(&mut buffer).is_err());
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(matches!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(Request::Info)
+        ));
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_text_codec_product_case() {
+        let mut codec = TextCodec::default();
+        let mut buffer = BytesMut::from(&b"BUY:apple\nSUB:Pear\n"[..]);
+        assert!(codec.decode(&mut buffer).is_err());
+        assert!(codec.decode(&mut buffer).is_err());
+
+        let mut codec = TextCodec::new(ProductCase::Insensitive);
+        let mut buffer = BytesMut::from(&b"BUY:apple\nSUB:Pear\nSELL:ApPlE@clid=a\n"[..]);
+        let Some(Request::Order(order)) = codec.decode(&mut buffer).unwrap() else {
+            panic!("Expected an order");
+        };
+        assert_eq!(order.product, Product::Apples);
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Subscribe(
+                Subscription::Product(Product::Pears),
+                false
+            ))
+        ));
+        // Only the product name is uppercased
+        let Some(Request::Order(order)) = codec.decode(&mut buffer).unwrap() else {
+            panic!("Expected an order");
+        };
+        assert_eq!(order.product, Product::Apples);
+        assert_eq!(order.client_order_id.as_deref(), Some("a"));
+        assert_eq!(
+            Product::parse_with_case("banana", ProductCase::Insensitive).unwrap(),
+            "BANANA".parse().unwrap()
+        );
+        assert!(Product::parse_with_case(&"a".repeat(17), ProductCase::Insensitive).is_err());
+    }
+
+    #[test]
+    fn test_text_codec_checks_crc_trailers() {
+        let mut codec = TextCodec::default();
+        let mut framed = b"OPTS:crc\n".to_vec();
+        append_with_crc(b"BUY:APPLE\nINFO\n", &mut framed);
+        let mut buffer = BytesMut::from(framed.as_slice());
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Options(ClientOption::Crc))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Order(_))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Request::Info)
+        ));
+
+        let mut corrupted = Vec::new();
+        append_with_crc(b"BUY:APPLE\n", &mut corrupted);
+        corrupted[4] = b'E';
+        buffer.extend_from_slice(&corrupted);
+        buffer.extend_from_slice(b"INFO\nINFO|crc=zz\n");
+        for _ in 0..3 {
+            let error = codec.decode(&mut buffer).unwrap_err();
+            assert_eq!(error.downcast_ref(), Some(&CrcMismatch));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_append_with_crc() {
+        let mut framed = Vec::new();
+        append_with_crc(b"ACK:APPLE\nPART:", &mut framed);
+        let expected = format!(
+            "ACK:APPLE|crc={:08x}\nPART:|crc={:08x}",
+            crc32(b"ACK:APPLE"),
+            crc32(b"PART:")
+        );
+        assert_eq!(framed, expected.as_bytes());
+        // The check value of CRC-32/ISO-HDLC
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_text_codec_encodes_frames() {
+        let mut codec = TextCodec::default();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                &Login {
+                    client_id: ClientId(7),
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        // Longer than the default scratch buffer used to size it
+        let text = "x".repeat(500);
+        codec
+            .encode(
+                &Message {
+                    origin_client_id: ClientId(8),
+                    message: text.clone(),
+                },
+                &mut buffer,
+            )
+            .unwrap();
+
+        assert_eq!(buffer, format!("LOGIN:7\nMESSAGE:8 {text}\n").as_bytes());
+    }
+
+    #[test]
+    fn test_text_codec_round_trips_orders() {
+        let mut codec = TextCodec::default();
+        let order = Order {
+            side: Side::Sell,
+            product: Product::Onions,
+            client_order_id: Some("abc".to_string()),
+            venue: Some("A".parse().unwrap()),
+        };
+        let mut buffer = BytesMut::new();
+        codec.encode(&order, &mut buffer).unwrap();
+
+        let Some(Request::Order(decoded)) = codec.decode(&mut buffer).unwrap() else {
+            panic!("Expected an order");
+        };
+        assert_eq!(decoded.side, order.side);
+        assert_eq!(decoded.product, order.product);
+        assert_eq!(decoded.client_order_id, order.client_order_id);
+    }
+}
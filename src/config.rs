@@ -0,0 +1,170 @@
+use std::{num::NonZeroUsize, path::Path, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    decoder::DecoderConfig,
+    encoder::EncoderConfig,
+    models::{Product, ProductCase},
+    products::ProductRegistry,
+    server::ServerConfig,
+};
+
+const DEFAULT_BIND: &str = "0.0.0.0:8888";
+
+/// Settings for the server, decoder and encoder, as loaded from a TOML file:
+///
+/// ```toml
+/// bind = "0.0.0.0:8888"
+/// products = ["APPLE", "PEAR"]
+/// max_connections = 1000
+/// handshake_timeout_ms = 5000
+/// ```
+///
+/// Every setting is optional, settings left out keep their defaults. Unknown settings are
+/// rejected, so typos do not go unnoticed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on.
+    pub bind: String,
+    /// Products that can be traded, the built-in ones if not set.
+    pub products: Option<Vec<String>>,
+    /// See `ServerConfig::max_connections`.
+    pub max_connections: Option<usize>,
+    /// See `ServerConfig::max_accept_rate`.
+    pub max_accept_rate: Option<u32>,
+    /// See `ServerConfig::max_in_flight_orders`.
+    pub max_in_flight_orders: Option<usize>,
+    /// See `DecoderConfig::max_pending_orders`.
+    pub max_pending_orders: Option<NonZeroUsize>,
+    /// See `DecoderConfig::max_line_rate`.
+    pub max_line_rate: Option<u32>,
+    /// Accept product names in requests in any case, see `DecoderConfig::product_case`.
+    pub case_insensitive_products: bool,
+    /// See `DecoderConfig::handshake_timeout`.
+    pub handshake_timeout_ms: Option<u64>,
+    /// See `DecoderConfig::idle_timeout`.
+    pub idle_timeout_ms: Option<u64>,
+    /// See `EncoderConfig::flush_interval`.
+    pub flush_interval_ms: Option<u64>,
+    /// See `EncoderConfig::ack_latency_target`.
+    pub ack_latency_target_ms: Option<u64>,
+    /// See `EncoderConfig::byte_quota`.
+    pub byte_quota: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND.to_string(),
+            products: None,
+            max_connections: None,
+            max_accept_rate: None,
+            max_in_flight_orders: None,
+            max_pending_orders: None,
+            max_line_rate: None,
+            case_insensitive_products: false,
+            handshake_timeout_ms: None,
+            idle_timeout_ms: None,
+            flush_interval_ms: None,
+            ack_latency_target_ms: None,
+            byte_quota: None,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).context("Invalid config")
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config from {}", path.display()))?
+            .parse()
+    }
+
+    pub fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let mut config = ServerConfig {
+            max_connections: self.max_connections,
+            max_accept_rate: self.max_accept_rate,
+            max_in_flight_orders: self.max_in_flight_orders,
+            ..ServerConfig::default()
+        };
+        if let Some(products) = &self.products {
+            let products = products
+                .iter()
+                .map(|product| product.parse())
+                .collect::<anyhow::Result<Vec<Product>>>()?;
+            config.products = ProductRegistry::new(products);
+        }
+
+        Ok(config)
+    }
+
+    #[must_use]
+    pub fn decoder_config(&self) -> DecoderConfig {
+        DecoderConfig {
+            handshake_timeout: self.handshake_timeout_ms.map(Duration::from_millis),
+            idle_timeout: self.idle_timeout_ms.map(Duration::from_millis),
+            max_pending_orders: self.max_pending_orders,
+            max_line_rate: self.max_line_rate,
+            product_case: if self.case_insensitive_products {
+                ProductCase::Insensitive
+            } else {
+                ProductCase::Sensitive
+            },
+            ..DecoderConfig::default()
+        }
+    }
+
+    #[must_use]
+    pub fn encoder_config(&self) -> EncoderConfig {
+        EncoderConfig {
+            flush_interval: self.flush_interval_ms.map(Duration::from_millis),
+            ack_latency_target: self.ack_latency_target_ms.map(Duration::from_millis),
+            byte_quota: self.byte_quota,
+            ..EncoderConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = r#"
+            bind = "127.0.0.1:9000"
+            products = ["APPLE", "BANANA"]
+            max_connections = 10
+            handshake_timeout_ms = 1500
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:9000");
+        let server_config = config.server_config().unwrap();
+        assert_eq!(server_config.max_connections, Some(10));
+        assert!(server_config.products.contains("BANANA".parse().unwrap()));
+        assert!(!server_config.products.contains(Product::Pears));
+        assert_eq!(
+            config.decoder_config().handshake_timeout,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(config.encoder_config().flush_interval, None);
+
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("max_conections = 10".parse::<Config>().is_err());
+        let config: Config = "products = [\"apple\"]".parse().unwrap();
+        assert!(config.server_config().is_err());
+    }
+}
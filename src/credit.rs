@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::models::ClientId;
+
+/// Caps the orders each client has in flight. An order holds its credit until the
+/// `OrderCredit` handed out for it is dropped, which happens once its ack has been written.
+#[derive(Debug)]
+pub struct OrderCredits {
+    limit: usize,
+    in_flight: HashMap<ClientId, Arc<AtomicUsize>>,
+}
+
+impl OrderCredits {
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Takes one of `client_id`'s credits, `None` if they are all in use.
+    pub fn try_acquire(&mut self, client_id: ClientId) -> Option<OrderCredit> {
+        let in_flight = self.in_flight.entry(client_id).or_default();
+        // Credits are only taken here, so the count can only drop between the check and the add
+        if in_flight.load(Ordering::Acquire) >= self.limit {
+            return None;
+        }
+        in_flight.fetch_add(1, Ordering::AcqRel);
+
+        Some(OrderCredit(in_flight.clone()))
+    }
+
+    /// Forgets a disconnected client. Credits still held for it are returned to nobody.
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.in_flight.remove(&client_id);
+    }
+}
+
+/// A credit taken from `OrderCredits`, returned when dropped.
+#[derive(Debug)]
+pub struct OrderCredit(Arc<AtomicUsize>);
+
+impl Drop for OrderCredit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credit_is_returned_on_drop() {
+        let mut credits = OrderCredits::new(2);
+        let first = credits.try_acquire(ClientId(1));
+        let second = credits.try_acquire(ClientId(1));
+        assert!(first.is_some() && second.is_some());
+        assert!(credits.try_acquire(ClientId(1)).is_none());
+        // Credits are per client
+        assert!(credits.try_acquire(ClientId(2)).is_some());
+
+        drop(first);
+        assert!(credits.try_acquire(ClientId(1)).is_some());
+    }
+}
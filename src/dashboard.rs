@@ -0,0 +1,99 @@
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::server::ServerEvent;
+
+/// Streams the `ServerEvent`s published on `events` to WebSocket clients on `listener`.
+///
+/// Each event is a JSON text message, see `to_json`. Dashboards only listen, and connect
+/// separately from the trading port. Runs until `cancellation_token` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    events: broadcast::Sender<ServerEvent>,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = tokio::select! {
+            () = cancellation_token.cancelled() => return Ok(()),
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Failed to accept dashboard connection: {e:?}");
+                    continue;
+                }
+            },
+        };
+
+        // Subscribed before the handshake, so the dashboard sees everything from here on
+        let receiver = events.subscribe();
+        let cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            match forward(stream, receiver, cancellation_token).await {
+                Ok(()) => tracing::info!("Dashboard {peer:?} disconnected"),
+                Err(e) => tracing::warn!("Dashboard {peer:?} failed: {e:?}"),
+            }
+        });
+    }
+}
+
+async fn forward(
+    stream: TcpStream,
+    mut receiver: broadcast::Receiver<ServerEvent>,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+    loop {
+        let event = tokio::select! {
+            () = cancellation_token.cancelled() => break,
+            event = receiver.recv() => event,
+            message = websocket.next() => match message {
+                None | Some(Ok(Message::Close(_))) => return Ok(()),
+                Some(Err(e)) => return Err(e.into()),
+                // Nothing to answer, pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+            },
+        };
+        match event {
+            Ok(event) => {
+                websocket
+                    .send(Message::text(to_json(&event).to_string()))
+                    .await?;
+            }
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Dashboard fell behind, {missed} events were skipped");
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    websocket.close(None).await?;
+
+    Ok(())
+}
+
+/// `event` as sent to dashboards, e.g. `{"event": "connected", "client_id": 51234}`.
+#[must_use]
+pub fn to_json(event: &ServerEvent) -> serde_json::Value {
+    match event {
+        ServerEvent::ClientConnected(client_id) => serde_json::json!({
+            "event": "connected",
+            "client_id": client_id.0,
+        }),
+        ServerEvent::ClientDisconnected(client_id) => serde_json::json!({
+            "event": "disconnected",
+            "client_id": client_id.0,
+        }),
+        ServerEvent::Trade(execution) => serde_json::json!({
+            "event": "trade",
+            "product": execution.product.as_str(),
+            "venue": execution.venue.map(|venue| venue.to_string()),
+            "trade_id": execution.trade_id,
+            "side": execution.side.as_str(),
+            "timestamp_ms": u64::try_from(execution.timestamp.as_millis()).unwrap_or(u64::MAX),
+        }),
+    }
+}
@@ -1,66 +1,385 @@
+use bytes::BytesMut;
+use futures::future::BoxFuture;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use std::collections::HashMap;
-use std::str::FromStr;
-use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
 use tokio::net::tcp::OwnedReadHalf;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{
+    mpsc::{error::SendError, Receiver, Sender},
+    oneshot, OwnedSemaphorePermit, Semaphore,
+};
+use tokio::time::Instant;
 
-use crate::models::{ClientId, Message, Order};
+use crate::clock::{Clock, TokioClock};
+use crate::codec::{Codec, CrcMismatch, TextCodec};
+use crate::models::{
+    ClientId, ClientOption, Message, Order, Product, ProductCase, Request, Subscription,
+};
+use crate::rate_limit::TokenBucket;
 
 #[derive(Debug)]
 pub enum DecoderTaskControl {
-    ClientAdded(ClientId, OwnedReadHalf),
+    /// Resolves once the encoder has sent the client its `LOGIN` frame, or closes if it failed to.
+    ClientAdded(ClientId, OwnedReadHalf, oneshot::Receiver<()>),
+    /// Stop reading from clients until `Resume`. Clients are not timed out in the meantime.
+    Pause,
+    Resume,
+    /// Drop a client the server disconnected, closing its read half. It is not reported back as
+    /// `DecoderEvent::ClientDisconnected`.
+    RemoveClient(ClientId),
 }
 
 #[derive(Debug)]
 pub enum DecoderEvent {
     ClientDisconnected(ClientId),
-    Order(ClientId, Order),
+    /// The permit, if any, lets the decoder read the client's next request once the server is
+    /// done with the order. Last is when the order was decoded, to measure ack latency.
+    Order(ClientId, Order, Option<OwnedSemaphorePermit>, Instant),
+    Message(Message),
+    Info(ClientId),
+    Options(ClientId, ClientOption),
+    /// Last is whether the client wants imbalance updates conflated.
+    Subscribe(ClientId, Subscription, bool),
+    Unsubscribe(ClientId, Subscription),
+    Tape(ClientId, Product, usize),
+    /// `PING`, answered right away whatever state the client is in.
+    Ping(ClientId),
+    QueueDepth(ClientId),
+    /// The first client asked to follow the second.
+    Follow(ClientId, ClientId),
+    /// A line from the client was dropped for a bad CRC trailer, see `ClientOption::Crc`.
+    CrcMismatch(ClientId),
+    /// The client sent no valid line within the handshake timeout and has been dropped.
+    HandshakeTimeout(ClientId),
+    /// The client will be dropped for being idle unless it sends something soon.
+    IdleWarning(ClientId),
+    /// The client sent no valid line within the idle timeout and has been dropped.
+    IdleTimeout(ClientId),
+}
+
+impl DecoderEvent {
+    /// The client the event came from.
+    #[must_use]
+    pub const fn client_id(&self) -> ClientId {
+        match self {
+            Self::Message(message) => message.origin_client_id,
+            Self::ClientDisconnected(client_id)
+            | Self::Order(client_id, ..)
+            | Self::Info(client_id)
+            | Self::Options(client_id, _)
+            | Self::Subscribe(client_id, ..)
+            | Self::Unsubscribe(client_id, _)
+            | Self::Tape(client_id, ..)
+            | Self::Ping(client_id)
+            | Self::QueueDepth(client_id)
+            | Self::Follow(client_id, _)
+            | Self::CrcMismatch(client_id)
+            | Self::HandshakeTimeout(client_id)
+            | Self::IdleWarning(client_id)
+            | Self::IdleTimeout(client_id) => *client_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecoderConfig {
+    /// Disconnect clients whose first valid line does not arrive within this window.
+    pub handshake_timeout: Option<Duration>,
+    /// Disconnect clients that send no valid line for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Send idle clients `WARN:IDLE` this long before the idle timeout disconnects them.
+    pub idle_warning: Option<Duration>,
+    /// Stop reading from a client while this many of its orders are waiting for the server, so
+    /// one busy client cannot crowd out the others.
+    pub max_pending_orders: Option<NonZeroUsize>,
+    /// Leave a client's lines unread until its `LOGIN` frame has been sent, so no response can
+    /// overtake the login.
+    pub await_login: bool,
+    /// Cap on lines read from a client per second, valid or not. A client over the cap is not
+    /// read from until it is back under, so it cannot keep the decoder busy for everyone. `PING`
+    /// is not counted, and answered even while the client is held back.
+    pub max_line_rate: Option<u32>,
+    /// Whether product names in requests have to be uppercase.
+    pub product_case: ProductCase,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for DecoderConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: None,
+            idle_timeout: None,
+            idle_warning: None,
+            max_pending_orders: None,
+            await_login: true,
+            max_line_rate: None,
+            product_case: ProductCase::default(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+/// Initial size of a client's read buffer. It grows as needed for longer requests.
+const READ_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug)]
+struct ClientReader {
+    read: OwnedReadHalf,
+    // Received bytes the codec has not consumed yet
+    buffer: BytesMut,
+    codec: TextCodec,
+    // Taken once the login has been sent
+    login_sent: Option<oneshot::Receiver<()>>,
+    // When the last valid line arrived, or the client connected
+    last_activity: Instant,
+    // Whether the client was sent `WARN:IDLE` since its last valid line
+    idle_warned: bool,
+    // One permit per order the server may have pending, see `max_pending_orders`
+    pending_orders: Option<Arc<Semaphore>>,
+    // See `max_line_rate`
+    line_limiter: Option<TokenBucket>,
+    // A line decoded while over `max_line_rate`, kept here until its turn so a dropped future
+    // loses no line
+    throttled: Option<anyhow::Result<Request>>,
 }
 
 #[derive(Debug, Default)]
 pub struct Decoder {
-    clients: HashMap<ClientId, Lines<BufReader<OwnedReadHalf>>>,
+    clients: HashMap<ClientId, ClientReader>,
+    config: DecoderConfig,
+    // Clients still waiting for their first valid line, with their deadline
+    pending_handshakes: HashMap<ClientId, Instant>,
+    // The same deadlines in connection order, which is also expiry order
+    handshake_deadlines: VecDeque<(Instant, ClientId)>,
+    paused: bool,
 }
 
 struct DecoderMessage {
     disconnected_clients: Vec<ClientId>,
-    message: Option<(ClientId, Order)>,
+    message: Option<(ClientId, Request, Option<OwnedSemaphorePermit>)>,
+    // Client whose line was dropped for a bad CRC trailer
+    crc_mismatch: Option<ClientId>,
 }
 
 pub enum ClientDecodeResult {
-    Ok(Order),
+    /// The permit, if any, is held until the server has handled the request if it is an order.
+    Ok(Request, Option<OwnedSemaphorePermit>),
     SocketError(std::io::Error),
     ClientDisconnected,
+    /// A line was dropped for a bad CRC trailer, see `ClientOption::Crc`.
+    CrcMismatch,
 }
 
 impl Decoder {
-    fn add_client(&mut self, client_id: ClientId, read: OwnedReadHalf) {
-        let buf_reader = BufReader::new(read);
-        self.clients.insert(client_id, buf_reader.lines());
+    #[must_use]
+    pub fn new(config: DecoderConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    fn add_client(
+        &mut self,
+        client_id: ClientId,
+        read: OwnedReadHalf,
+        login_sent: oneshot::Receiver<()>,
+    ) {
+        if self.clients.contains_key(&client_id) {
+            // Dropping `read` leaves the duplicate to be closed, the encoder refuses it as well
+            tracing::error!("{client_id:?} is already connected, dropping the duplicate");
+            return;
+        }
+        let reader = ClientReader {
+            read,
+            buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            codec: TextCodec::new(self.config.product_case),
+            login_sent: self.config.await_login.then_some(login_sent),
+            last_activity: self.config.clock.now(),
+            idle_warned: false,
+            pending_orders: self
+                .config
+                .max_pending_orders
+                .map(|max| Arc::new(Semaphore::new(max.get()))),
+            line_limiter: self
+                .config
+                .max_line_rate
+                .map(|rate| TokenBucket::new(rate, self.config.clock.now())),
+            throttled: None,
+        };
+        self.clients.insert(client_id, reader);
+
+        if let Some(handshake_timeout) = self.config.handshake_timeout {
+            let deadline = self.config.clock.now() + handshake_timeout;
+            self.pending_handshakes.insert(client_id, deadline);
+            self.handshake_deadlines.push_back((deadline, client_id));
+        }
+    }
+
+    fn remove_client(&mut self, client_id: ClientId) {
+        self.clients.remove(&client_id);
+        self.pending_handshakes.remove(&client_id);
+    }
+
+    /// Continues reading from clients. Their handshake and idle timers start over, as they could
+    /// not be heard from while paused.
+    fn resume(&mut self) {
+        self.paused = false;
+        let now = self.config.clock.now();
+        for reader in self.clients.values_mut() {
+            reader.last_activity = now;
+        }
+        if let Some(handshake_timeout) = self.config.handshake_timeout {
+            let deadline = now + handshake_timeout;
+            self.handshake_deadlines.clear();
+            for (client_id, client_deadline) in &mut self.pending_handshakes {
+                *client_deadline = deadline;
+                self.handshake_deadlines.push_back((deadline, *client_id));
+            }
+        }
+    }
+
+    fn next_handshake_deadline(&self) -> BoxFuture<'static, ()> {
+        match self.handshake_deadlines.front() {
+            Some((deadline, _)) => self.config.clock.sleep_until(*deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Drops every client whose handshake deadline has passed, returning their ids.
+    fn expire_handshakes(&mut self) -> Vec<ClientId> {
+        let now = self.config.clock.now();
+        let mut expired = Vec::new();
+        while let Some(&(deadline, client_id)) = self.handshake_deadlines.front() {
+            if deadline > now {
+                break;
+            }
+            self.handshake_deadlines.pop_front();
+
+            // Skip clients that completed the handshake, or reconnected under the same id
+            if self.pending_handshakes.get(&client_id) == Some(&deadline) {
+                self.remove_client(client_id);
+                expired.push(client_id);
+            }
+        }
+
+        expired
+    }
+
+    /// When `reader` is next due an idle warning or disconnect, `None` without an idle timeout.
+    fn idle_deadline(&self, reader: &ClientReader) -> Option<Instant> {
+        let idle_timeout = self.config.idle_timeout?;
+        let idle_for = match self.config.idle_warning {
+            Some(idle_warning) if !reader.idle_warned => idle_timeout.saturating_sub(idle_warning),
+            _ => idle_timeout,
+        };
+
+        Some(reader.last_activity + idle_for)
+    }
+
+    fn next_idle_deadline(&self) -> BoxFuture<'static, ()> {
+        let deadline = self
+            .clients
+            .values()
+            .filter_map(|reader| self.idle_deadline(reader))
+            .min();
+        match deadline {
+            Some(deadline) => self.config.clock.sleep_until(deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Warns or drops every client whose idle deadline has passed, returning the events to
+    /// report.
+    fn expire_idle_clients(&mut self) -> Vec<DecoderEvent> {
+        let now = self.config.clock.now();
+        let due: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, reader)| self.idle_deadline(reader).is_some_and(|d| d <= now))
+            .map(|(client_id, _)| *client_id)
+            .collect();
+
+        let mut events = Vec::with_capacity(due.len());
+        for client_id in due {
+            let Some(reader) = self.clients.get_mut(&client_id) else {
+                continue;
+            };
+            if self.config.idle_warning.is_some() && !reader.idle_warned {
+                reader.idle_warned = true;
+                events.push(DecoderEvent::IdleWarning(client_id));
+            } else {
+                self.remove_client(client_id);
+                events.push(DecoderEvent::IdleTimeout(client_id));
+            }
+        }
+
+        events
     }
 
     async fn next_message_client(
         client_id: &ClientId,
-        lines: &mut Lines<BufReader<OwnedReadHalf>>,
+        reader: &mut ClientReader,
+        clock: &dyn Clock,
     ) -> (ClientId, ClientDecodeResult) {
+        if let Some(login_sent) = &mut reader.login_sent {
+            if login_sent.await.is_err() {
+                tracing::warn!("Login was never sent to {client_id:?}");
+                return (*client_id, ClientDecodeResult::ClientDisconnected);
+            }
+            reader.login_sent = None;
+        }
+
+        // Waits for the server to catch up with the client's earlier orders
+        let permit = match &reader.pending_orders {
+            Some(pending_orders) => match Arc::clone(pending_orders).acquire_owned().await {
+                Ok(permit) => Some(permit),
+                Err(_) => return (*client_id, ClientDecodeResult::ClientDisconnected),
+            },
+            None => None,
+        };
+
+        let mut eof = false;
         loop {
-            let next_line = match lines.next_line().await {
-                Ok(Some(line)) => line,
-                Ok(None) => return (*client_id, ClientDecodeResult::ClientDisconnected),
-                Err(e) => return (*client_id, ClientDecodeResult::SocketError(e)),
+            let decoded = match reader.throttled.take() {
+                Some(throttled) => throttled.map(Some),
+                None if eof => reader.codec.decode_eof(&mut reader.buffer),
+                None => reader.codec.decode(&mut reader.buffer),
             };
-
-            let order = match Order::from_str(&next_line) {
-                Ok(o) => o,
+            // Charged for every line, valid or not, but `PING` so it stays a liveness probe
+            if let Some(line_limiter) = &mut reader.line_limiter {
+                let charged = !matches!(decoded, Ok(None | Some(Request::Ping)));
+                if charged && !line_limiter.try_acquire(clock.now()) {
+                    reader.throttled = decoded.transpose();
+                    clock.sleep(line_limiter.time_until_available()).await;
+                    continue;
+                }
+            }
+            match decoded {
+                Ok(Some(request)) => return (*client_id, ClientDecodeResult::Ok(request, permit)),
+                Ok(None) if eof => return (*client_id, ClientDecodeResult::ClientDisconnected),
+                Ok(None) => {}
+                Err(e) if e.is::<CrcMismatch>() => {
+                    tracing::warn!("Line with a bad CRC from {client_id:?}");
+                    return (*client_id, ClientDecodeResult::CrcMismatch);
+                }
                 Err(e) => {
                     tracing::warn!("Invalid request from {:?}: {:?}", client_id, e);
                     continue;
                 }
-            };
+            }
 
-            return (*client_id, ClientDecodeResult::Ok(order));
+            match reader.read.read_buf(&mut reader.buffer).await {
+                // The lines still buffered, a trailing one without newline included, are decoded
+                // before the disconnect is reported
+                Ok(0) => eof = true,
+                Ok(_) => {}
+                Err(e) => return (*client_id, ClientDecodeResult::SocketError(e)),
+            }
         }
     }
 
@@ -70,11 +389,16 @@ impl Decoder {
             return Ok(DecoderMessage {
                 disconnected_clients: Vec::new(),
                 message: None,
+                crc_mismatch: None,
             });
         }
         let mut futures = FuturesUnordered::new();
-        for (client_id, lines) in &mut self.clients {
-            futures.push(Self::next_message_client(client_id, lines));
+        for (client_id, reader) in &mut self.clients {
+            futures.push(Self::next_message_client(
+                client_id,
+                reader,
+                self.config.clock.as_ref(),
+            ));
         }
 
         let mut disconnected_clients = Vec::new();
@@ -86,10 +410,18 @@ impl Decoder {
             };
 
             match result {
-                ClientDecodeResult::Ok(order) => {
+                ClientDecodeResult::Ok(request, permit) => {
                     return Ok(DecoderMessage {
                         disconnected_clients,
-                        message: Some((client_id, order)),
+                        message: Some((client_id, request, permit)),
+                        crc_mismatch: None,
+                    });
+                }
+                ClientDecodeResult::CrcMismatch => {
+                    return Ok(DecoderMessage {
+                        disconnected_clients,
+                        message: None,
+                        crc_mismatch: Some(client_id),
                     });
                 }
                 ClientDecodeResult::SocketError(_error) => {
@@ -105,23 +437,45 @@ impl Decoder {
         Ok(DecoderMessage {
             disconnected_clients,
             message: None,
+            crc_mismatch: None,
         })
     }
 
+    /// Runs until the control channel closes, or the server stops taking events. Both are part
+    /// of a normal shutdown.
     pub async fn run(
         &mut self,
-        mut receiver: Receiver<DecoderTaskControl>,
+        receiver: Receiver<DecoderTaskControl>,
         sender: Sender<DecoderEvent>,
     ) -> anyhow::Result<()> {
         tracing::info!("Decoder started");
+        if self.decode_until_closed(receiver, &sender).await.is_err() {
+            tracing::info!("Decoder: Event channel closed");
+        }
+
+        Ok(())
+    }
+
+    async fn decode_until_closed(
+        &mut self,
+        mut receiver: Receiver<DecoderTaskControl>,
+        sender: &Sender<DecoderEvent>,
+    ) -> Result<(), SendError<DecoderEvent>> {
         loop {
+            let handshake_deadline = self.next_handshake_deadline();
+            let idle_deadline = self.next_idle_deadline();
             tokio::select! {
                 message = receiver.recv() => {
                     if let Some(m) = message {
                         tracing::debug!("Decoder: {:?}", m);
                         match m {
-                            DecoderTaskControl::ClientAdded(client_id, read) => {
-                                self.add_client(client_id, read);
+                            DecoderTaskControl::ClientAdded(client_id, read, login_sent) => {
+                                self.add_client(client_id, read, login_sent);
+                            }
+                            DecoderTaskControl::Pause => self.paused = true,
+                            DecoderTaskControl::Resume => self.resume(),
+                            DecoderTaskControl::RemoveClient(client_id) => {
+                                self.remove_client(client_id);
                             }
                         }
                     } else {
@@ -129,8 +483,8 @@ impl Decoder {
                         return Ok(());
                     }
                 }
-                message = self.decode_message() => {
-                    let DecoderMessage { disconnected_clients, message } = match message {
+                message = self.decode_message(), if !self.paused => {
+                    let DecoderMessage { disconnected_clients, message, crc_mismatch } = match message {
                         Ok(m) => m,
                         Err(e) => {
                             tracing::error!("Error decoding message: {:?}", e);
@@ -140,12 +494,61 @@ impl Decoder {
 
                     for client_id in disconnected_clients {
                         sender.send(DecoderEvent::ClientDisconnected(client_id)).await?;
-                        self.clients.remove(&client_id);
+                        self.remove_client(client_id);
+                    }
+                    if let Some(client_id) = crc_mismatch {
+                        sender.send(DecoderEvent::CrcMismatch(client_id)).await?;
                     }
 
-                    if let Some((client_id, order)) = message {
-                        tracing::info!("Message from client {client_id:?}: {order:?}");
-                        sender.send(DecoderEvent::Order(client_id, order)).await?;
+                    if let Some((client_id, request, permit)) = message {
+                        tracing::info!("Message from client {client_id:?}: {request:?}");
+                        // Liveness probes neither complete the handshake nor keep a client alive
+                        if !matches!(request, Request::Ping) {
+                            self.pending_handshakes.remove(&client_id);
+                            if let Some(reader) = self.clients.get_mut(&client_id) {
+                                reader.last_activity = self.config.clock.now();
+                                reader.idle_warned = false;
+                            }
+                        }
+                        let event = match request {
+                            Request::Ping => DecoderEvent::Ping(client_id),
+                            Request::QueueDepth => DecoderEvent::QueueDepth(client_id),
+                            Request::Follow(followed) => DecoderEvent::Follow(client_id, followed),
+                            Request::Order(order) => DecoderEvent::Order(
+                                client_id,
+                                order,
+                                permit,
+                                self.config.clock.now(),
+                            ),
+                            Request::Message(message) => DecoderEvent::Message(Message {
+                                origin_client_id: client_id,
+                                message,
+                            }),
+                            Request::Info => DecoderEvent::Info(client_id),
+                            Request::Options(option) => DecoderEvent::Options(client_id, option),
+                            Request::Subscribe(subscription, conflate) => {
+                                DecoderEvent::Subscribe(client_id, subscription, conflate)
+                            }
+                            Request::Unsubscribe(subscription) => {
+                                DecoderEvent::Unsubscribe(client_id, subscription)
+                            }
+                            Request::Tape(product, count) => {
+                                DecoderEvent::Tape(client_id, product, count)
+                            }
+                        };
+                        sender.send(event).await?;
+                    }
+                }
+                () = handshake_deadline, if !self.paused => {
+                    for client_id in self.expire_handshakes() {
+                        tracing::warn!("Client {client_id:?} did not complete the handshake in time");
+                        sender.send(DecoderEvent::HandshakeTimeout(client_id)).await?;
+                    }
+                }
+                () = idle_deadline, if !self.paused => {
+                    for event in self.expire_idle_clients() {
+                        tracing::warn!("Idle client: {event:?}");
+                        sender.send(event).await?;
                     }
                 }
             }
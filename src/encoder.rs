@@ -1,24 +1,663 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use flate2::{Compress, Compression, FlushCompress};
+use futures::future::BoxFuture;
 
-use anyhow::Context;
-use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf, sync::mpsc::Receiver};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::tcp::OwnedWriteHalf,
+    sync::{mpsc::Receiver, oneshot},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    matcher::Match,
-    models::{ClientId, Encode, Login, OrderAck, Trade},
+    clock::{Clock, TokioClock},
+    codec::{append_with_crc, Codec, TextCodec},
+    credit::OrderCredit,
+    models::{
+        Bye, ClientId, ClientOption, CompressionAck, Echo, Encode, Execution, FollowAck,
+        IdleWarning, Imbalance, Info, Login, Message, MessageAck, Nack, OrderAck, Pong, Product,
+        QueueDepth, RejectReason, Snapshot, Subscription, SubscriptionAck, Tape, Trade, Trades,
+    },
 };
 
 #[derive(Debug)]
 pub enum EncoderTaskControl {
-    ClientAdded(ClientId, OwnedWriteHalf),
+    /// The sender is notified once the `LOGIN` frame has been written.
+    ClientAdded(ClientId, OwnedWriteHalf, oneshot::Sender<()>),
     ClientDisconnected(ClientId),
-    OrderAck(ClientId, OrderAck),
-    Match(Match),
+    /// The credit, if any, is returned once the ack has been written. Last is when the order
+    /// was decoded, `None` for orders not read from a client.
+    OrderAck(ClientId, OrderAck, Option<OrderCredit>, Option<Instant>),
+    Echo(ClientId, Echo),
+    Nack(ClientId, Nack),
+    Info(ClientId, Info),
+    SetOption(ClientId, ClientOption),
+    SubscriptionAck(ClientId, SubscriptionAck),
+    FollowAck(ClientId, FollowAck),
+    Snapshot(ClientId, Snapshot),
+    Tape(ClientId, Tape),
+    MessageAck(ClientId),
+    /// Sent ahead of the `ClientDisconnected` for clients disconnected by a drain.
+    Bye(ClientId),
+    IdleWarning(ClientId),
+    Pong(ClientId),
+    /// Answers `QDEPTH` with the client's current queue depth.
+    QueueDepth(ClientId),
+    Message(Message),
+    /// Sends a frame to the clients accepted by the filter. Pre-encoded frames can be sent as
+    /// `FrameBytes`.
+    BroadcastIf(ClientFilter, Box<dyn Encode>),
+    /// Like `BroadcastIf`, but clients that enabled `ClientOption::BatchTrades` receive the trade
+    /// in their next `TRADES:` frame, and ones that enabled `ClientOption::RichTrades` as `EXEC:`.
+    Trade(ClientFilter, Execution),
+    /// Like `BroadcastIf`, but while batching clients that subscribed with `@conflate` only get
+    /// the latest imbalance of the product queued since their last flush.
+    Imbalance(ClientFilter, Imbalance),
+}
+
+/// Selects the recipients of a `BroadcastIf`, e.g. the subscribers of a product.
+pub struct ClientFilter(pub Box<dyn Fn(&ClientId) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for ClientFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientFilter")
+    }
+}
+
+/// A client is flushed early once this many bytes are waiting for the batching window.
+const MAX_BATCH_BYTES: usize = 16 * 1024;
+const DEFAULT_ENCODE_BUFFER_SIZE: usize = 1024;
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+/// Smallest accepted frame cap, so a split or truncated frame always has room for its payload.
+const MIN_FRAME_SIZE: usize = 64;
+const DEFAULT_TRADE_BATCH_TIMEOUT: Duration = Duration::from_millis(100);
+const DEFAULT_WRITE_RETRIES: u32 = 2;
+const DEFAULT_WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(5);
+/// Room for `NACK:QUOTA`, sent past the byte quota.
+const QUOTA_NACK_LEN: usize = 16;
+/// Prefix of every part of a split frame except the last.
+pub const FRAME_PART_PREFIX: &[u8] = b"PART:";
+/// Replaces the end of a truncated frame.
+pub const TRUNCATED_MARKER: &[u8] = b"[TRUNCATED]\n";
+
+/// What to do with frames longer than `EncoderConfig::max_frame_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizeFramePolicy {
+    /// Cut the frame short, ending it with `TRUNCATED_MARKER`.
+    #[default]
+    Truncate,
+    /// Send the frame as `PART:<chunk>` frames followed by the remainder. Clients reassemble it
+    /// by concatenating the chunks and the remainder.
+    Split,
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    /// When set, frames are buffered per client and written once per interval, or as soon as
+    /// `MAX_BATCH_BYTES` are pending. Frames for a single client, like acks, are written ahead of
+    /// the broadcasts queued with them. `None` writes every frame immediately.
+    pub flush_interval: Option<Duration>,
+    /// Initial size of the encode buffer. It grows as needed for larger frames.
+    pub encode_buffer_size: usize,
+    /// Largest frame sent to clients, including the newline. See `oversize_frames`.
+    pub max_frame_size: usize,
+    pub oversize_frames: OversizeFramePolicy,
+    /// Longest a trade waits in a partially filled trade batch before the batch is sent anyway.
+    pub trade_batch_timeout: Duration,
+    /// Holds trades back from subscribers for this long after they matched, e.g. for fairness
+    /// experiments. Acks to the traders themselves are not delayed. `None` sends trades right
+    /// away.
+    pub market_data_delay: Option<Duration>,
+    /// Acks handed to a client's connection later than this after their order was decoded are
+    /// logged and counted in `EncoderStats::slow_acks`. Measured with `clock`, which has to be
+    /// the decoder's too.
+    pub ack_latency_target: Option<Duration>,
+    /// Most bytes sent to a client over its connection, counted before CRC trailers and
+    /// compression. A frame that would go over it is dropped, and the client gets `NACK:QUOTA`
+    /// instead and its connection is shut down for writing. `None` sends without limit.
+    pub byte_quota: Option<u64>,
+    /// How often a write failing with a transient error is retried before the write fails.
+    pub write_retries: u32,
+    /// Wait before the first retry, doubled for every further retry and jittered.
+    pub write_retry_backoff: Duration,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: None,
+            encode_buffer_size: DEFAULT_ENCODE_BUFFER_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            oversize_frames: OversizeFramePolicy::default(),
+            trade_batch_timeout: DEFAULT_TRADE_BATCH_TIMEOUT,
+            market_data_delay: None,
+            ack_latency_target: None,
+            byte_quota: None,
+            write_retries: DEFAULT_WRITE_RETRIES,
+            write_retry_backoff: DEFAULT_WRITE_RETRY_BACKOFF,
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+/// Write counters, shared so they can be read while the encoder runs.
+#[derive(Debug, Default)]
+pub struct EncoderStats {
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    slow_acks: AtomicU64,
+    clients: Mutex<HashMap<ClientId, ClientStats>>,
+}
+
+/// Counters of a connected client.
+#[derive(Debug, Default, Clone, Copy)]
+struct ClientStats {
+    // Bytes waiting for the next flush
+    queue_depth: usize,
+    // Bytes sent so far, before compression
+    bytes_sent: u64,
+}
+
+impl EncoderStats {
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Acks that missed `EncoderConfig::ack_latency_target`.
+    pub fn slow_acks(&self) -> u64 {
+        self.slow_acks.load(Ordering::Relaxed)
+    }
+
+    /// Bytes queued for `client_id` that have not been written yet, to spot clients falling
+    /// behind. Only grows when batching, `None` for clients that are not connected.
+    pub fn queue_depth(&self, client_id: ClientId) -> Option<usize> {
+        self.lock_clients()
+            .get(&client_id)
+            .map(|client| client.queue_depth)
+    }
+
+    /// Bytes sent to `client_id` since it connected, before compression, see
+    /// `EncoderConfig::byte_quota`. `None` for clients that are not connected.
+    pub fn bytes_sent(&self, client_id: ClientId) -> Option<u64> {
+        self.lock_clients()
+            .get(&client_id)
+            .map(|client| client.bytes_sent)
+    }
+
+    fn record_queue_depth(&self, client_id: ClientId, depth: usize) {
+        self.lock_clients()
+            .entry(client_id)
+            .or_default()
+            .queue_depth = depth;
+    }
+
+    fn record_bytes_sent(&self, client_id: ClientId, bytes_sent: u64) {
+        self.lock_clients().entry(client_id).or_default().bytes_sent = bytes_sent;
+    }
+
+    fn forget_client(&self, client_id: ClientId) {
+        self.lock_clients().remove(&client_id);
+    }
+
+    fn lock_clients(&self) -> std::sync::MutexGuard<'_, HashMap<ClientId, ClientStats>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record_write(&self, length: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(length as u64, Ordering::Relaxed);
+    }
+}
+
+/// Scratch space frames are encoded into. Reused for every frame, and a broadcast frame is
+/// encoded once for all of its recipients.
+#[derive(Debug)]
+pub struct EncodeBuffer {
+    buffer: BytesMut,
+    codec: TextCodec,
+    max_frame_size: usize,
+    oversize_frames: OversizeFramePolicy,
+    // Output for frames over the cap
+    capped: Vec<u8>,
+}
+
+impl Default for EncodeBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENCODE_BUFFER_SIZE)
+    }
+}
+
+impl EncodeBuffer {
+    #[must_use]
+    pub fn new(size: usize) -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(size),
+            codec: TextCodec::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            oversize_frames: OversizeFramePolicy::default(),
+            capped: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_max_frame_size(
+        mut self,
+        max_frame_size: usize,
+        policy: OversizeFramePolicy,
+    ) -> Self {
+        self.max_frame_size = max_frame_size.max(MIN_FRAME_SIZE);
+        self.oversize_frames = policy;
+        self
+    }
+
+    /// Encodes `message`, returning the bytes to send: the frame itself, or the frames it was
+    /// truncated or split into. They stay valid until the next call.
+    pub fn encode<T: Encode + ?Sized>(&mut self, message: &T) -> anyhow::Result<&[u8]> {
+        self.buffer.clear();
+        self.codec.encode(message, &mut self.buffer)?;
+
+        let frame = &self.buffer[..];
+        if frame.len() <= self.max_frame_size {
+            return Ok(frame);
+        }
+
+        tracing::warn!(
+            "Frame of {} bytes exceeds the {} byte cap: {:?}",
+            frame.len(),
+            self.max_frame_size,
+            self.oversize_frames,
+        );
+        self.capped.clear();
+        match self.oversize_frames {
+            OversizeFramePolicy::Truncate => {
+                let end = char_boundary(frame, self.max_frame_size - TRUNCATED_MARKER.len());
+                self.capped.extend_from_slice(&frame[..end]);
+                self.capped.extend_from_slice(TRUNCATED_MARKER);
+            }
+            OversizeFramePolicy::Split => {
+                let mut remaining = frame;
+                // The last part keeps the frame's own newline
+                while remaining.len() > self.max_frame_size {
+                    let end =
+                        char_boundary(remaining, self.max_frame_size - FRAME_PART_PREFIX.len() - 1);
+                    self.capped.extend_from_slice(FRAME_PART_PREFIX);
+                    self.capped.extend_from_slice(&remaining[..end]);
+                    self.capped.push(b'\n');
+                    remaining = &remaining[end..];
+                }
+                self.capped.extend_from_slice(remaining);
+            }
+        }
+
+        Ok(&self.capped)
+    }
+}
+
+/// Largest index up to `index` that does not split a UTF-8 character.
+fn char_boundary(bytes: &[u8], mut index: usize) -> usize {
+    // Continuation bytes look like 0b10xx_xxxx
+    while index > 0 && bytes[index] & 0xC0 == 0x80 {
+        index -= 1;
+    }
+
+    index
+}
+
+/// How writes failing with a transient error are retried, see `EncoderConfig::write_retries`.
+#[derive(Debug, Clone)]
+struct WriteRetry {
+    retries: u32,
+    backoff: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl WriteRetry {
+    fn new(config: &EncoderConfig) -> Self {
+        Self {
+            retries: config.write_retries,
+            backoff: config.write_retry_backoff,
+            clock: config.clock.clone(),
+        }
+    }
+
+    /// Half of the doubled backoff, plus up to as much again at random, so clients that failed
+    /// together do not retry in lockstep.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff.saturating_mul(2u32.saturating_pow(attempt)) / 2;
+        let random = RandomState::new().build_hasher().finish();
+        // Only the proportion matters, precision loss is fine
+        #[allow(clippy::cast_precision_loss)]
+        let jitter = backoff.mul_f64(random as f64 / u64::MAX as f64);
+
+        backoff + jitter
+    }
+
+    /// Writes all of `bytes`, picking up after a short write where it left off. Only the part
+    /// not written yet is retried, so a frame is never sent twice.
+    async fn write_all<W: AsyncWrite + Unpin>(
+        &self,
+        write: &mut W,
+        bytes: &[u8],
+    ) -> std::io::Result<()> {
+        let mut written = 0;
+        let mut attempt = 0;
+        while written < bytes.len() {
+            match write.write(&bytes[written..]).await {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(length) => written += length,
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!("Write failed with {e:?}, retrying in {delay:?}");
+                    self.clock.sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// The peer is gone, e.g. it closed right after sending its last orders.
+fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+    )
+}
+
+#[derive(Debug)]
+struct TradeBatch {
+    size: NonZeroUsize,
+    trades: Vec<Trade>,
+}
+
+/// Which of a client's queues a frame waits in when batching. Control frames are written ahead
+/// of the queued market data, so a burst of broadcasts does not hold up a client's own acks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    /// Frames for this client only: acks, nacks, replies to requests.
+    Control,
+    /// Frames broadcast to many clients, e.g. trades and imbalances.
+    MarketData,
+}
+
+#[derive(Debug)]
+struct ClientWriter {
+    client_id: ClientId,
+    write: OwnedWriteHalf,
+    // Frames waiting for the next flush when batching is enabled, one queue per `Priority`
+    pending_control: Vec<u8>,
+    pending_market_data: Vec<u8>,
+    // Latest imbalance frame per product of the conflated subscriptions, written after the
+    // rest of the queued market data
+    pending_conflated: HashMap<Product, Vec<u8>>,
+    // Subscriptions made with `@conflate`
+    conflated: HashSet<Subscription>,
+    // Set once the client enabled `ClientOption::BatchTrades`
+    trade_batch: Option<TradeBatch>,
+    // Set once the client enabled `ClientOption::NackOnly`
+    nack_only: bool,
+    // Set once the client enabled `ClientOption::RichTrades`
+    rich_trades: bool,
+    // Set once the client enabled `ClientOption::Crc`, scratch space for its frames with their
+    // CRC trailers
+    crc_buffer: Option<Vec<u8>>,
+    // Set once the client enabled `ClientOption::Compress`, the state of its deflate stream
+    compressor: Option<Compress>,
+    // Set once a write found the peer gone. Frames are dropped from then on, until the server
+    // reports the disconnect the decoder saw
+    closed: bool,
+    // Bytes sent so far before compression, and the most that may be, see
+    // `EncoderConfig::byte_quota`
+    bytes_sent: u64,
+    byte_quota: Option<u64>,
+    retry: WriteRetry,
+}
+
+impl ClientWriter {
+    fn new(client_id: ClientId, write: OwnedWriteHalf, retry: WriteRetry) -> Self {
+        Self {
+            client_id,
+            write,
+            pending_control: Vec::new(),
+            pending_market_data: Vec::new(),
+            pending_conflated: HashMap::new(),
+            conflated: HashSet::new(),
+            trade_batch: None,
+            nack_only: false,
+            rich_trades: false,
+            crc_buffer: None,
+            compressor: None,
+            closed: false,
+            bytes_sent: 0,
+            byte_quota: None,
+            retry,
+        }
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8], stats: &EncoderStats) -> anyhow::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if self
+            .byte_quota
+            .is_some_and(|quota| self.bytes_sent + bytes.len() as u64 > quota)
+        {
+            return self.close_over_quota(stats).await;
+        }
+        self.write_frames(bytes, stats).await
+    }
+
+    /// Writes `bytes`, with CRC trailers and compressed if the client asked for them, whatever
+    /// the byte quota.
+    async fn write_frames(&mut self, bytes: &[u8], stats: &EncoderStats) -> anyhow::Result<()> {
+        // Taken for the write, so it can be borrowed alongside the connection
+        let mut crc_buffer = self.crc_buffer.take();
+        let framed = crc_buffer.as_mut().map_or(bytes, |crc_buffer| {
+            crc_buffer.clear();
+            append_with_crc(bytes, crc_buffer);
+            crc_buffer.as_slice()
+        });
+        let compressed;
+        let written = match &mut self.compressor {
+            Some(compressor) => {
+                compressed = deflate(compressor, framed)?;
+                compressed.as_slice()
+            }
+            None => framed,
+        };
+        let length = written.len();
+        let result = self.retry.write_all(&mut self.write, written).await;
+        self.crc_buffer = crc_buffer;
+        match result {
+            // Not a reason to stop writing to everyone else
+            Err(e) if is_disconnect(&e) => {
+                tracing::warn!("Peer is gone, dropping its frames: {e:?}");
+                self.closed = true;
+                return Ok(());
+            }
+            result => result?,
+        }
+        stats.record_write(length);
+        self.bytes_sent += bytes.len() as u64;
+        stats.record_bytes_sent(self.client_id, self.bytes_sent);
+
+        Ok(())
+    }
+
+    /// Tells the client it used up its byte quota and shuts its connection down for writing.
+    /// Its frames are dropped from then on, until the decoder sees it disconnect.
+    async fn close_over_quota(&mut self, stats: &EncoderStats) -> anyhow::Result<()> {
+        tracing::warn!(
+            "{:?} is over its byte quota after {} bytes, closing",
+            self.client_id,
+            self.bytes_sent
+        );
+        let mut frame = [0; QUOTA_NACK_LEN];
+        let length = Nack::from(RejectReason::Quota).encode(&mut frame)?;
+        self.write_frames(&frame[..length], stats).await?;
+        self.closed = true;
+        // Best effort, the client may be gone already
+        let _ = self.write.shutdown().await;
+
+        Ok(())
+    }
+
+    /// Compresses everything written from now on. Frames queued so far and `ack` still go out
+    /// uncompressed, so the client can tell where the deflate stream starts.
+    async fn enable_compression(&mut self, ack: &[u8], stats: &EncoderStats) -> anyhow::Result<()> {
+        self.flush(stats).await?;
+        // Sent compressed if the client asks again, it is already reading the stream then
+        self.write_bytes(ack, stats).await?;
+        if self.compressor.is_none() {
+            self.compressor = Some(Compress::new(Compression::default(), false));
+        }
+
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.pending_control.is_empty()
+            || !self.pending_market_data.is_empty()
+            || !self.pending_conflated.is_empty()
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.pending_control.len()
+            + self.pending_market_data.len()
+            + self.pending_conflated.values().map(Vec::len).sum::<usize>()
+    }
+
+    fn conflates(&self, product: Product) -> bool {
+        self.conflated.contains(&Subscription::Product(product))
+            || self.conflated.contains(&Subscription::AllProducts)
+    }
+
+    /// Queues `frame` in place of the imbalance of `product` still waiting for the flush.
+    async fn conflate(
+        &mut self,
+        product: Product,
+        frame: &[u8],
+        stats: &EncoderStats,
+    ) -> anyhow::Result<()> {
+        let pending = self.pending_conflated.entry(product).or_default();
+        pending.clear();
+        pending.extend_from_slice(frame);
+        if self.queue_depth() >= MAX_BATCH_BYTES {
+            self.flush(stats).await?;
+        } else {
+            stats.record_queue_depth(self.client_id, self.queue_depth());
+        }
+
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        frame: &[u8],
+        priority: Priority,
+        batching: bool,
+        stats: &EncoderStats,
+    ) -> anyhow::Result<()> {
+        if !batching {
+            return self.write_bytes(frame, stats).await;
+        }
+
+        let pending = match priority {
+            Priority::Control => &mut self.pending_control,
+            Priority::MarketData => &mut self.pending_market_data,
+        };
+        pending.extend_from_slice(frame);
+        if self.queue_depth() >= MAX_BATCH_BYTES {
+            self.flush(stats).await?;
+        } else {
+            stats.record_queue_depth(self.client_id, self.queue_depth());
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self, stats: &EncoderStats) -> anyhow::Result<()> {
+        if !self.has_pending() {
+            return Ok(());
+        }
+
+        // Control frames first, still in a single write
+        let mut pending = std::mem::take(&mut self.pending_control);
+        pending.extend_from_slice(&self.pending_market_data);
+        self.pending_market_data.clear();
+        for (_, frame) in self.pending_conflated.drain() {
+            pending.extend_from_slice(&frame);
+        }
+        let result = self.write_bytes(&pending, stats).await;
+        // Keep the allocation around for the next batch
+        pending.clear();
+        self.pending_control = pending;
+        stats.record_queue_depth(self.client_id, 0);
+
+        result
+    }
+}
+
+/// Compresses `bytes` as the next part of a raw deflate stream. The output is sync flushed, so
+/// the client can decompress all of it without waiting for more.
+fn deflate(compressor: &mut Compress, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(bytes.len() + 64);
+    let mut input = bytes;
+    loop {
+        let total_in = compressor.total_in();
+        compressor.compress_vec(input, &mut output, FlushCompress::Sync)?;
+        let consumed = usize::try_from(compressor.total_in() - total_in)?;
+        input = &input[consumed..];
+        // Output left over once the output buffer has room to spare
+        if input.is_empty() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+        output.reserve(output.capacity());
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Encoder {
-    clients: HashMap<ClientId, OwnedWriteHalf>,
+    clients: HashMap<ClientId, ClientWriter>,
+    config: EncoderConfig,
+    stats: Arc<EncoderStats>,
+    flush_deadline: Option<Instant>,
+    trade_batch_deadline: Option<Instant>,
+    // Trades held back by `market_data_delay`, with when they are due, oldest first
+    delayed_trades: VecDeque<(Instant, ClientFilter, Execution)>,
+    buffer: EncodeBuffer,
 }
 
 impl Drop for Encoder {
@@ -30,32 +669,370 @@ impl Drop for Encoder {
 }
 
 impl Encoder {
+    #[must_use]
+    pub fn new(config: EncoderConfig) -> Self {
+        Self {
+            clients: HashMap::new(),
+            buffer: EncodeBuffer::new(config.encode_buffer_size)
+                .with_max_frame_size(config.max_frame_size, config.oversize_frames),
+            config,
+            stats: Arc::default(),
+            flush_deadline: None,
+            trade_batch_deadline: None,
+            delayed_trades: VecDeque::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> Arc<EncoderStats> {
+        self.stats.clone()
+    }
+
     async fn shutdown(&mut self) {
         tracing::info!("Encoder: Shutdown");
+        // Best effort, the connections are closed either way
+        while let Some((_, filter, execution)) = self.delayed_trades.pop_front() {
+            let _ = self
+                .broadcast_trade(&execution, |client_id| (filter.0)(client_id))
+                .await;
+        }
+        let _ = self.send_trade_batches(true).await;
+        let stats = &self.stats;
         let iter = self
             .clients
             .drain()
-            .map(|(client_id, mut write)| async move {
+            .map(|(client_id, mut client)| async move {
                 tracing::info!("Sending shutdown to {client_id:?}");
-                write.shutdown().await
+                let _ = client.flush(stats).await;
+                client.write.shutdown().await
             });
 
         // We do not care if it fails. We are shutting down anyway
         futures::future::join_all(iter).await;
     }
 
-    fn add_client(&mut self, client_id: ClientId, write: OwnedWriteHalf) {
-        self.clients.insert(client_id, write);
+    fn add_client(&mut self, client_id: ClientId, client: ClientWriter) {
+        self.stats
+            .record_queue_depth(client_id, client.queue_depth());
+        self.clients.insert(client_id, client);
     }
 
-    async fn send<T: Encode>(message: &T, writer: &mut OwnedWriteHalf) -> anyhow::Result<()> {
-        let mut buffer = [0; 1024];
-        let length = message.encode(&mut buffer)?;
-        let sent_length = writer.write(&buffer[..length]).await?;
-        anyhow::ensure!(
-            sent_length == length,
-            "Expected to send {length} bytes but sent {sent_length}",
-        );
+    const fn batching(&self) -> bool {
+        self.config.flush_interval.is_some()
+    }
+
+    /// Sends `ack`, first noting whether the client wants the subscription conflated.
+    async fn send_subscription_ack(
+        &mut self,
+        client_id: ClientId,
+        ack: &SubscriptionAck,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            if ack.subscribed && ack.conflate {
+                client.conflated.insert(ack.subscription);
+            } else {
+                client.conflated.remove(&ack.subscription);
+            }
+        }
+
+        self.send_to(client_id, ack).await
+    }
+
+    /// Sends `order_ack` unless the client only wants to hear about rejections.
+    async fn send_order_ack(
+        &mut self,
+        client_id: ClientId,
+        order_ack: &OrderAck,
+        decoded_at: Option<Instant>,
+    ) -> anyhow::Result<()> {
+        let nack_only = self
+            .clients
+            .get(&client_id)
+            .is_some_and(|client| client.nack_only);
+        if !nack_only {
+            self.send_to(client_id, order_ack).await?;
+            if let Some(decoded_at) = decoded_at {
+                self.check_ack_latency(client_id, decoded_at);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Warns about and counts an ack that took longer than `ack_latency_target` to reach the
+    /// client's connection.
+    fn check_ack_latency(&self, client_id: ClientId, decoded_at: Instant) {
+        let Some(target) = self.config.ack_latency_target else {
+            return;
+        };
+        let latency = self
+            .config
+            .clock
+            .now()
+            .saturating_duration_since(decoded_at);
+        if latency > target {
+            tracing::warn!("Ack to {client_id:?} took {latency:?}, target is {target:?}");
+            self.stats.slow_acks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends a control frame to a single client. A client that disconnected while the frame was
+    /// in flight is not an error, the frame is dropped.
+    async fn send_to<T: Encode>(&mut self, client_id: ClientId, message: &T) -> anyhow::Result<()> {
+        self.send_with_priority(client_id, message, Priority::Control)
+            .await
+    }
+
+    async fn send_with_priority<T: Encode>(
+        &mut self,
+        client_id: ClientId,
+        message: &T,
+        priority: Priority,
+    ) -> anyhow::Result<()> {
+        let batching = self.batching();
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            tracing::warn!("Dropping {message:?} for unknown client {client_id:?}");
+            return Ok(());
+        };
+
+        let frame = self.buffer.encode(message)?;
+        client.send(frame, priority, batching, &self.stats).await
+    }
+
+    /// Sends an imbalance to every client accepted by `filter`, conflated for the clients that
+    /// asked for it. Without batching every frame is written right away, so there is nothing
+    /// to conflate.
+    async fn broadcast_imbalance(
+        &mut self,
+        imbalance: &Imbalance,
+        filter: impl Fn(&ClientId) -> bool,
+    ) -> anyhow::Result<()> {
+        let batching = self.batching();
+        let frame = self.buffer.encode(imbalance)?;
+        for (client_id, client) in &mut self.clients {
+            if !filter(client_id) {
+                continue;
+            }
+            if batching && client.conflates(imbalance.product) {
+                client
+                    .conflate(imbalance.product, frame, &self.stats)
+                    .await?;
+            } else {
+                client
+                    .send(frame, Priority::MarketData, batching, &self.stats)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a frame to every client accepted by `filter`.
+    async fn broadcast<T: Encode + ?Sized>(
+        &mut self,
+        message: &T,
+        filter: impl Fn(&ClientId) -> bool,
+    ) -> anyhow::Result<()> {
+        let batching = self.batching();
+        let frame = self.buffer.encode(message)?;
+        for (client_id, client) in &mut self.clients {
+            if !filter(client_id) {
+                continue;
+            }
+            client
+                .send(frame, Priority::MarketData, batching, &self.stats)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a trade to every client accepted by `filter`: as `EXEC:` to clients that want rich
+    /// trades, queued for clients batching trades and as `TRADE:` to the rest.
+    async fn broadcast_trade(
+        &mut self,
+        execution: &Execution,
+        filter: impl Fn(&ClientId) -> bool,
+    ) -> anyhow::Result<()> {
+        let batching = self.batching();
+        let trade = Trade {
+            product: execution.product,
+            venue: execution.venue,
+        };
+        let frame = self.buffer.encode(&trade)?;
+        for (client_id, client) in &mut self.clients {
+            if client.rich_trades || !filter(client_id) {
+                continue;
+            }
+            match &mut client.trade_batch {
+                Some(trade_batch) => trade_batch.trades.push(trade),
+                None => {
+                    client
+                        .send(frame, Priority::MarketData, batching, &self.stats)
+                        .await?;
+                }
+            }
+        }
+        // Only encoded when someone wants it, the buffer holds one frame at a time
+        if self.clients.values().any(|c| c.rich_trades) {
+            let frame = self.buffer.encode(execution)?;
+            for (client_id, client) in &mut self.clients {
+                if client.rich_trades && filter(client_id) {
+                    client
+                        .send(frame, Priority::MarketData, batching, &self.stats)
+                        .await?;
+                }
+            }
+        }
+
+        self.send_trade_batches(false).await?;
+        let queued = self
+            .clients
+            .values()
+            .any(|c| c.trade_batch.as_ref().is_some_and(|b| !b.trades.is_empty()));
+        if queued && self.trade_batch_deadline.is_none() {
+            self.trade_batch_deadline =
+                Some(self.config.clock.now() + self.config.trade_batch_timeout);
+        }
+
+        Ok(())
+    }
+
+    /// Sends the full trade batches, or every non-empty one when `partial` is set.
+    async fn send_trade_batches(&mut self, partial: bool) -> anyhow::Result<()> {
+        let batching = self.batching();
+        for client in self.clients.values_mut() {
+            let Some(trade_batch) = &mut client.trade_batch else {
+                continue;
+            };
+            let full = trade_batch.trades.len() >= trade_batch.size.get();
+            if trade_batch.trades.is_empty() || !(full || partial) {
+                continue;
+            }
+
+            let trades = Trades {
+                trades: std::mem::take(&mut trade_batch.trades),
+            };
+            let frame = self.buffer.encode(&trades)?;
+            client
+                .send(frame, Priority::MarketData, batching, &self.stats)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_option(
+        &mut self,
+        client_id: ClientId,
+        option: ClientOption,
+    ) -> anyhow::Result<()> {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            tracing::warn!("Dropping {option:?} for unknown client {client_id:?}");
+            return Ok(());
+        };
+
+        let result = match option {
+            ClientOption::Nagle => client
+                .write
+                .as_ref()
+                .set_nodelay(false)
+                .map_err(anyhow::Error::from),
+            // Trades already queued go out with the resized batch
+            ClientOption::BatchTrades(size) => {
+                match &mut client.trade_batch {
+                    Some(trade_batch) => trade_batch.size = size,
+                    None => {
+                        client.trade_batch = Some(TradeBatch {
+                            size,
+                            trades: Vec::new(),
+                        });
+                    }
+                }
+                Ok(())
+            }
+            ClientOption::NackOnly => {
+                client.nack_only = true;
+                Ok(())
+            }
+            ClientOption::RichTrades => {
+                client.rich_trades = true;
+                Ok(())
+            }
+            // Followers are kept by the server
+            ClientOption::NoFollow => Ok(()),
+            ClientOption::Crc => {
+                // Frames queued so far go out as they were when queued
+                let result = client.flush(&self.stats).await;
+                client.crc_buffer.get_or_insert_with(Vec::new);
+                result
+            }
+            ClientOption::Compress => {
+                let ack = self.buffer.encode(&CompressionAck)?;
+                client.enable_compression(ack, &self.stats).await
+            }
+        };
+        // Only this client is affected, not a reason to stop writing to everyone else
+        match result {
+            Ok(()) => tracing::info!("Set {option:?} for {client_id:?}"),
+            Err(e) => tracing::warn!("Failed to set {option:?} for {client_id:?}: {e:?}"),
+        }
+
+        Ok(())
+    }
+
+    async fn flush_all(&mut self) -> anyhow::Result<()> {
+        for client in self.clients.values_mut() {
+            client.flush(&self.stats).await?;
+        }
+
+        Ok(())
+    }
+
+    fn schedule_flush(&mut self) {
+        let Some(flush_interval) = self.config.flush_interval else {
+            return;
+        };
+
+        if self.flush_deadline.is_none() && self.clients.values().any(ClientWriter::has_pending) {
+            self.flush_deadline = Some(self.config.clock.now() + flush_interval);
+        }
+    }
+
+    fn flush_due(&self) -> BoxFuture<'static, ()> {
+        match self.flush_deadline {
+            Some(deadline) => self.config.clock.sleep_until(deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    fn trade_batch_due(&self) -> BoxFuture<'static, ()> {
+        match self.trade_batch_deadline {
+            Some(deadline) => self.config.clock.sleep_until(deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    fn delayed_trades_due(&self) -> BoxFuture<'static, ()> {
+        match self.delayed_trades.front() {
+            Some((deadline, _, _)) => self.config.clock.sleep_until(*deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Broadcasts the delayed trades that are due.
+    async fn send_delayed_trades(&mut self) -> anyhow::Result<()> {
+        let now = self.config.clock.now();
+        while self
+            .delayed_trades
+            .front()
+            .is_some_and(|(deadline, _, _)| *deadline <= now)
+        {
+            if let Some((_, filter, execution)) = self.delayed_trades.pop_front() {
+                self.broadcast_trade(&execution, |client_id| (filter.0)(client_id))
+                    .await?;
+            }
+        }
 
         Ok(())
     }
@@ -65,10 +1042,22 @@ impl Encoder {
         client_id: ClientId,
         mut write: OwnedWriteHalf,
     ) -> anyhow::Result<()> {
+        if self.clients.contains_key(&client_id) {
+            // The connection already known by that id is left alone. Best effort, the duplicate
+            // is closed once the decoder drops its read half too
+            let _ = write.shutdown().await;
+            anyhow::bail!("{client_id:?} is already connected, closing the duplicate");
+        }
         let login = Login { client_id };
         tracing::info!("Sending login message to client: {login:?}");
-        Self::send(&login, &mut write).await?;
-        self.add_client(client_id, write);
+        let mut client = ClientWriter::new(client_id, write, WriteRetry::new(&self.config));
+        client.byte_quota = self.config.byte_quota;
+        // Never batched, the login is the first thing a client sees
+        let frame = self.buffer.encode(&login)?;
+        client
+            .send(frame, Priority::Control, false, &self.stats)
+            .await?;
+        self.add_client(client_id, client);
 
         Ok(())
     }
@@ -80,10 +1069,12 @@ impl Encoder {
         if let Some(m) = message {
             tracing::debug!("Encoder: {:?}", m);
             match m {
-                EncoderTaskControl::ClientAdded(client_id, write) => {
+                EncoderTaskControl::ClientAdded(client_id, write, login_sent) => {
                     match self.on_new_connection(client_id, write).await {
                         Ok(()) => {
                             tracing::info!("Client {:?} added", client_id);
+                            // The decoder may have already dropped the client
+                            let _ = login_sent.send(());
                         }
                         Err(e) => {
                             tracing::error!("Failed to add {client_id:?}: {e:?}");
@@ -91,21 +1082,86 @@ impl Encoder {
                     }
                 }
                 EncoderTaskControl::ClientDisconnected(client_id) => {
-                    self.clients.remove(&client_id);
+                    if let Some(mut client) = self.clients.remove(&client_id) {
+                        // Deliver anything still batched, e.g. a final NACK. The client may
+                        // already be gone, so failing here is expected
+                        let _ = client.flush(&self.stats).await;
+                    }
+                    self.stats.forget_client(client_id);
                 }
-                EncoderTaskControl::OrderAck(client_id, order_ack) => {
-                    let client = self
-                        .clients
-                        .get_mut(&client_id)
-                        .context("Client not found")?;
-
-                    Self::send(&order_ack, client).await?;
+                EncoderTaskControl::OrderAck(client_id, order_ack, _credit, decoded_at) => {
+                    self.send_order_ack(client_id, &order_ack, decoded_at)
+                        .await?;
+                }
+                EncoderTaskControl::Echo(client_id, echo) => {
+                    self.send_to(client_id, &echo).await?;
                 }
-                EncoderTaskControl::Match(m) => {
-                    for (_, write) in &mut self.clients {
-                        let trade = Trade { product: m.product };
-                        Self::send(&trade, write).await?;
+                EncoderTaskControl::Nack(client_id, nack) => {
+                    self.send_to(client_id, &nack).await?;
+                }
+                EncoderTaskControl::Info(client_id, info) => {
+                    self.send_to(client_id, &info).await?;
+                }
+                EncoderTaskControl::SetOption(client_id, option) => {
+                    self.set_option(client_id, option).await?;
+                }
+                EncoderTaskControl::SubscriptionAck(client_id, ack) => {
+                    self.send_subscription_ack(client_id, &ack).await?;
+                }
+                EncoderTaskControl::FollowAck(client_id, ack) => {
+                    self.send_to(client_id, &ack).await?;
+                }
+                EncoderTaskControl::Snapshot(client_id, snapshot) => {
+                    // Must not overtake trades already queued for the client, the snapshot
+                    // includes them
+                    self.send_with_priority(client_id, &snapshot, Priority::MarketData)
+                        .await?;
+                }
+                EncoderTaskControl::Tape(client_id, tape) => {
+                    self.send_to(client_id, &tape).await?;
+                }
+                EncoderTaskControl::BroadcastIf(filter, message) => {
+                    self.broadcast(message.as_ref(), |client_id| (filter.0)(client_id))
+                        .await?;
+                }
+                EncoderTaskControl::Imbalance(filter, imbalance) => {
+                    self.broadcast_imbalance(&imbalance, |client_id| (filter.0)(client_id))
+                        .await?;
+                }
+                EncoderTaskControl::Trade(filter, trade) => match self.config.market_data_delay {
+                    Some(delay) => {
+                        let deadline = self.config.clock.now() + delay;
+                        self.delayed_trades.push_back((deadline, filter, trade));
+                    }
+                    None => {
+                        self.broadcast_trade(&trade, |client_id| (filter.0)(client_id))
+                            .await?;
                     }
+                },
+                EncoderTaskControl::MessageAck(client_id) => {
+                    self.send_to(client_id, &MessageAck).await?;
+                }
+                EncoderTaskControl::Bye(client_id) => {
+                    self.send_to(client_id, &Bye).await?;
+                }
+                EncoderTaskControl::IdleWarning(client_id) => {
+                    self.send_to(client_id, &IdleWarning).await?;
+                }
+                EncoderTaskControl::Pong(client_id) => {
+                    self.send_to(client_id, &Pong).await?;
+                }
+                EncoderTaskControl::QueueDepth(client_id) => {
+                    // Unknown clients are skipped by `send_to`
+                    let depth = self
+                        .clients
+                        .get(&client_id)
+                        .map_or(0, ClientWriter::queue_depth);
+                    self.send_to(client_id, &QueueDepth { depth }).await?;
+                }
+                EncoderTaskControl::Message(message) => {
+                    let origin_client_id = message.origin_client_id;
+                    self.broadcast(&message, |client_id| *client_id != origin_client_id)
+                        .await?;
                 }
             }
         } else {
@@ -116,19 +1172,618 @@ impl Encoder {
         Ok(())
     }
 
-    pub async fn run(&mut self, mut receiver: Receiver<EncoderTaskControl>) -> anyhow::Result<()> {
+    /// Runs until the channel closes or `flush_token` is cancelled. Either way every frame already
+    /// queued is written out before the client sockets are shut down.
+    pub async fn run(
+        &mut self,
+        mut receiver: Receiver<EncoderTaskControl>,
+        flush_token: CancellationToken,
+    ) -> anyhow::Result<()> {
         tracing::info!("Encoder started");
         loop {
             tracing::info!("Encoder - Waiting for message...");
             tokio::select! {
                 biased;
                 message = receiver.recv() =>  {
+                    if message.is_none() {
+                        // The server only lets go of the channel once it is done, same as a flush
+                        tracing::info!("Encoder: Channel closed");
+                        self.shutdown().await;
+                        return Ok(());
+                    }
                     self.handle_control_message(message).await?;
+                    self.schedule_flush();
+                }
+                () = self.flush_due() => {
+                    self.flush_deadline = None;
+                    self.flush_all().await?;
+                }
+                () = self.trade_batch_due() => {
+                    self.trade_batch_deadline = None;
+                    self.send_trade_batches(true).await?;
+                    self.schedule_flush();
+                }
+                () = self.delayed_trades_due() => {
+                    self.send_delayed_trades().await?;
+                    self.schedule_flush();
+                }
+                () = flush_token.cancelled() => {
+                    tracing::info!("Encoder: Flushing queued frames");
+                    while let Ok(message) = receiver.try_recv() {
+                        self.handle_control_message(Some(message)).await?;
+                    }
+                    self.shutdown().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::Write,
+        pin::Pin,
+        task::{Context as TaskContext, Poll},
+    };
+
+    use crate::models::{FrameBytes, Product, Side};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, BufReader, Lines},
+        net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Adds a client to `encoder` and returns the client's end of the connection, past the login.
+    async fn add_client(
+        encoder: &mut Encoder,
+        client_id: ClientId,
+    ) -> Lines<BufReader<OwnedReadHalf>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let (_, write) = stream.into_split();
+
+        let (login_sent, _) = oneshot::channel();
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::ClientAdded(
+                client_id, write, login_sent,
+            )))
+            .await
+            .unwrap();
+
+        let (read, _) = client.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let login = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(login, format!("LOGIN:{client_id}"));
+
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_nagle_option_disables_nodelay() {
+        let mut encoder = Encoder::default();
+        let client_id = ClientId(1);
+        let _lines = add_client(&mut encoder, client_id).await;
+        assert!(encoder.clients[&client_id]
+            .write
+            .as_ref()
+            .nodelay()
+            .unwrap());
+
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::SetOption(
+                client_id,
+                ClientOption::Nagle,
+            )))
+            .await
+            .unwrap();
+        assert!(!encoder.clients[&client_id]
+            .write
+            .as_ref()
+            .nodelay()
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_option_for_unknown_client_is_dropped() {
+        let mut encoder = Encoder::default();
+        let mut lines = add_client(&mut encoder, ClientId(1)).await;
+
+        for option in [
+            ClientOption::Nagle,
+            ClientOption::Crc,
+            ClientOption::Compress,
+        ] {
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::SetOption(ClientId(2), option)))
+                .await
+                .unwrap();
+        }
+
+        // Still writing to everyone else
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::Pong(ClientId(1))))
+            .await
+            .unwrap();
+        encoder.flush_all().await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_if_skips_filtered_clients() {
+        let mut encoder = Encoder::default();
+        let mut subscriber = add_client(&mut encoder, ClientId(1)).await;
+        let mut other = add_client(&mut encoder, ClientId(2)).await;
+
+        let subscribers = HashSet::from([ClientId(1)]);
+        let filter = ClientFilter(Box::new(move |client_id| subscribers.contains(client_id)));
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::BroadcastIf(
+                filter,
+                Box::new(FrameBytes(b"TRADE:APPLE\n".to_vec())),
+            )))
+            .await
+            .unwrap();
+        // Closes the connections, so the other client sees EOF instead of the trade
+        encoder.shutdown().await;
+
+        assert_eq!(
+            subscriber.next_line().await.unwrap().as_deref(),
+            Some("TRADE:APPLE")
+        );
+        assert_eq!(other.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_ack_overtakes_queued_market_data() {
+        let config = EncoderConfig {
+            flush_interval: Some(Duration::from_secs(30)),
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let client_id = ClientId(1);
+        let mut lines = add_client(&mut encoder, client_id).await;
+
+        for _ in 0..100 {
+            let filter = ClientFilter(Box::new(|_| true));
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::BroadcastIf(
+                    filter,
+                    Box::new(FrameBytes(b"TRADE:APPLE\n".to_vec())),
+                )))
+                .await
+                .unwrap();
+        }
+        let ack = OrderAck {
+            product: Product::Pears,
+            client_order_id: None,
+        };
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::OrderAck(
+                client_id, ack, None, None,
+            )))
+            .await
+            .unwrap();
+        encoder.shutdown().await;
+
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("ACK:PEAR")
+        );
+        for _ in 0..100 {
+            assert_eq!(
+                lines.next_line().await.unwrap().as_deref(),
+                Some("TRADE:APPLE")
+            );
+        }
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_client_is_closed() {
+        let mut encoder = Encoder::default();
+        let client_id = ClientId(1);
+        let mut lines = add_client(&mut encoder, client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let duplicate = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (_, write) = stream.into_split();
+        let (login_sent, login_sent_receiver) = oneshot::channel();
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::ClientAdded(
+                client_id, write, login_sent,
+            )))
+            .await
+            .unwrap();
+        assert!(login_sent_receiver.await.is_err());
+        let mut duplicate_lines = BufReader::new(duplicate).lines();
+        assert_eq!(duplicate_lines.next_line().await.unwrap(), None);
+
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::Pong(client_id)))
+            .await
+            .unwrap();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("PONG"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_depth_grows_until_flushed() {
+        let config = EncoderConfig {
+            flush_interval: Some(Duration::from_secs(30)),
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let stats = encoder.stats();
+        let client_id = ClientId(1);
+        let mut lines = add_client(&mut encoder, client_id).await;
+        assert_eq!(stats.queue_depth(client_id), Some(0));
+        assert_eq!(stats.queue_depth(ClientId(2)), None);
+
+        // Nothing is written before the flush, as if the client did not keep up
+        for _ in 0..3 {
+            let filter = ClientFilter(Box::new(|_| true));
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::BroadcastIf(
+                    filter,
+                    Box::new(FrameBytes(b"TRADE:APPLE\n".to_vec())),
+                )))
+                .await
+                .unwrap();
+        }
+        assert_eq!(stats.queue_depth(client_id), Some(36));
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::QueueDepth(client_id)))
+            .await
+            .unwrap();
+        assert_eq!(stats.queue_depth(client_id), Some(46));
+
+        encoder.flush_all().await.unwrap();
+        assert_eq!(stats.queue_depth(client_id), Some(0));
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("QDEPTH:36")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_over_byte_quota_is_closed() {
+        let config = EncoderConfig {
+            byte_quota: Some(40),
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let stats = encoder.stats();
+        let client_id = ClientId(1);
+        let mut lines = add_client(&mut encoder, client_id).await;
+        assert_eq!(stats.bytes_sent(client_id), Some(8));
+
+        for _ in 0..5 {
+            let filter = ClientFilter(Box::new(|_| true));
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::BroadcastIf(
+                    filter,
+                    Box::new(FrameBytes(b"TRADE:APPLE\n".to_vec())),
+                )))
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            assert_eq!(
+                lines.next_line().await.unwrap().as_deref(),
+                Some("TRADE:APPLE")
+            );
+        }
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("NACK:QUOTA")
+        );
+        assert_eq!(lines.next_line().await.unwrap(), None);
+        // Login, two trades and the nack
+        assert_eq!(stats.bytes_sent(client_id), Some(43));
+    }
+
+    #[tokio::test]
+    async fn test_conflated_subscribers_get_the_latest_imbalance() {
+        let config = EncoderConfig {
+            flush_interval: Some(Duration::from_secs(30)),
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let (conflated, every_update) = (ClientId(1), ClientId(2));
+        let mut conflated_lines = add_client(&mut encoder, conflated).await;
+        let mut every_update_lines = add_client(&mut encoder, every_update).await;
+        for (client_id, conflate) in [(conflated, true), (every_update, false)] {
+            let ack = SubscriptionAck {
+                subscription: Subscription::Product(Product::Apples),
+                subscribed: true,
+                conflate,
+            };
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::SubscriptionAck(client_id, ack)))
+                .await
+                .unwrap();
+        }
+
+        // Nothing is written before the flush, as if the clients did not keep up
+        for value in [1.0, 0.0, -1.0] {
+            let imbalance = Imbalance {
+                product: Product::Apples,
+                value,
+            };
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::Imbalance(
+                    ClientFilter(Box::new(|_| true)),
+                    imbalance,
+                )))
+                .await
+                .unwrap();
+        }
+        encoder.flush_all().await.unwrap();
+        drop(encoder);
+
+        let mut lines = Vec::new();
+        while let Some(line) = conflated_lines.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines, ["ACK:SUB:APPLE@conflate", "IMBAL:APPLE:-1.00"]);
+        let mut lines = Vec::new();
+        while let Some(line) = every_update_lines.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(
+            lines,
+            [
+                "ACK:SUB:APPLE",
+                "IMBAL:APPLE:1.00",
+                "IMBAL:APPLE:0.00",
+                "IMBAL:APPLE:-1.00"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compressed_stream_decodes_to_the_frames() {
+        let config = EncoderConfig {
+            flush_interval: Some(Duration::from_secs(30)),
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let client_id = ClientId(1);
+        let lines = add_client(&mut encoder, client_id).await;
+
+        let trade = || {
+            EncoderTaskControl::BroadcastIf(
+                ClientFilter(Box::new(|_| true)),
+                Box::new(FrameBytes(b"TRADE:APPLE\n".to_vec())),
+            )
+        };
+        // Queued before compression is enabled, so still sent as is
+        encoder.handle_control_message(Some(trade())).await.unwrap();
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::SetOption(
+                client_id,
+                ClientOption::Compress,
+            )))
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            encoder.handle_control_message(Some(trade())).await.unwrap();
+        }
+        encoder.flush_all().await.unwrap();
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::Bye(client_id)))
+            .await
+            .unwrap();
+        encoder.shutdown().await;
+
+        let mut received = Vec::new();
+        lines.into_inner().read_to_end(&mut received).await.unwrap();
+        let plain = b"TRADE:APPLE\nACK:COMPRESS\n";
+        assert_eq!(&received[..plain.len()], plain);
+        let compressed = &received[plain.len()..];
+
+        // The stream is never finished, clients decompress it as it arrives
+        let mut decoder = flate2::write::DeflateDecoder::new(Vec::new());
+        decoder.write_all(compressed).unwrap();
+        decoder.flush().unwrap();
+        let decompressed = String::from_utf8(decoder.get_ref().clone()).unwrap();
+        assert_eq!(
+            decompressed,
+            format!("{}BYE\n", "TRADE:APPLE\n".repeat(100))
+        );
+        assert!(compressed.len() < decompressed.len() / 4);
+    }
+
+    /// In-memory transport whose first writes fail with a transient error, and that takes at
+    /// most `max_write` bytes per write if set.
+    #[derive(Default)]
+    struct FlakyWriter {
+        failures: usize,
+        max_write: Option<usize>,
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+            bytes: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(ErrorKind::TimedOut.into()));
+            }
+            let length = self.max_write.unwrap_or(bytes.len()).min(bytes.len());
+            self.written.extend_from_slice(&bytes[..length]);
+            Poll::Ready(Ok(length))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_write_error_is_retried() {
+        let retry = WriteRetry::new(&EncoderConfig::default());
+        let mut write = FlakyWriter {
+            failures: 1,
+            ..FlakyWriter::default()
+        };
+        retry.write_all(&mut write, b"ACK:APPLE\n").await.unwrap();
+        assert_eq!(write.written, b"ACK:APPLE\n");
+
+        // Gives up once the retries are used up
+        let mut write = FlakyWriter {
+            failures: 3,
+            ..FlakyWriter::default()
+        };
+        let error = retry
+            .write_all(&mut write, b"ACK:APPLE\n")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(write.written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_short_writes_are_completed() {
+        let retry = WriteRetry::new(&EncoderConfig::default());
+        let mut write = FlakyWriter {
+            failures: 1,
+            max_write: Some(3),
+            ..FlakyWriter::default()
+        };
+        retry
+            .write_all(&mut write, b"ACK:APPLE@clid=1\n")
+            .await
+            .unwrap();
+        assert_eq!(write.written, b"ACK:APPLE@clid=1\n");
+    }
+
+    fn execution(product: Product) -> Execution {
+        Execution {
+            product,
+            trade_id: 1,
+            side: Side::Buy,
+            timestamp: Duration::ZERO,
+            venue: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_trades_arrive_in_one_frame() {
+        let mut encoder = Encoder::default();
+        let client_id = ClientId(1);
+        let mut lines = add_client(&mut encoder, client_id).await;
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::SetOption(
+                client_id,
+                ClientOption::BatchTrades(NonZeroUsize::new(3).unwrap()),
+            )))
+            .await
+            .unwrap();
+
+        for product in [Product::Apples, Product::Pears, Product::Apples] {
+            let filter = ClientFilter(Box::new(|_| true));
+            encoder
+                .handle_control_message(Some(EncoderTaskControl::Trade(filter, execution(product))))
+                .await
+                .unwrap();
+        }
+        // A partial batch waits for the timeout, or for shutdown
+        let filter = ClientFilter(Box::new(|_| true));
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::Trade(
+                filter,
+                execution(Product::Pears),
+            )))
+            .await
+            .unwrap();
+        encoder.shutdown().await;
+
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("TRADES:APPLE,PEAR,APPLE")
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("TRADES:PEAR")
+        );
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_oversize_frame_is_split() {
+        let config = EncoderConfig {
+            max_frame_size: 64,
+            oversize_frames: OversizeFramePolicy::Split,
+            ..EncoderConfig::default()
+        };
+        let mut encoder = Encoder::new(config);
+        let mut lines = add_client(&mut encoder, ClientId(1)).await;
+        let _sender = add_client(&mut encoder, ClientId(2)).await;
+
+        let text = "é".repeat(100);
+        encoder
+            .handle_control_message(Some(EncoderTaskControl::Message(Message {
+                origin_client_id: ClientId(2),
+                message: text.clone(),
+            })))
+            .await
+            .unwrap();
+        encoder.shutdown().await;
+
+        let mut reassembled = String::new();
+        let mut parts = 0;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            assert!(line.len() < 64, "Frame over the cap: {line}");
+            match line.strip_prefix("PART:") {
+                Some(part) => {
+                    reassembled.push_str(part);
+                    parts += 1;
                 }
-                // message = server_event_receiver.recv() => {
-                //     self.handle_server_message(message).await?;
-                // }
+                None => reassembled.push_str(&line),
             }
         }
+
+        assert!(parts > 1);
+        assert_eq!(reassembled, format!("MESSAGE:2 {text}"));
+    }
+
+    #[test]
+    fn test_oversize_frame_is_truncated() {
+        let mut buffer =
+            EncodeBuffer::new(16).with_max_frame_size(64, OversizeFramePolicy::Truncate);
+        let message = Message {
+            origin_client_id: ClientId(1),
+            message: "x".repeat(100),
+        };
+
+        let frame = buffer.encode(&message).unwrap();
+
+        assert_eq!(frame.len(), 64);
+        assert!(frame.starts_with(b"MESSAGE:1 xxx"));
+        assert!(frame.ends_with(TRUNCATED_MARKER));
     }
 }
@@ -0,0 +1,71 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::models::ClientId;
+
+/// Client order ids seen within the last `window`, used to reject duplicate submissions.
+#[derive(Debug)]
+pub struct RecentOrderIds {
+    window: Duration,
+    seen: HashSet<(ClientId, String)>,
+    // Insertion order, so expired ids can be evicted from the front
+    expiry: VecDeque<(Instant, ClientId, String)>,
+}
+
+impl RecentOrderIds {
+    #[must_use]
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashSet::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    /// Records `client_order_id` for `client_id`. Returns `false` if it was already seen within
+    /// the window.
+    pub fn insert(&mut self, now: Instant, client_id: ClientId, client_order_id: &str) -> bool {
+        self.evict_expired(now);
+
+        if !self.seen.insert((client_id, client_order_id.to_string())) {
+            return false;
+        }
+        self.expiry
+            .push_back((now, client_id, client_order_id.to_string()));
+
+        true
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        while let Some((inserted, _, _)) = self.expiry.front() {
+            if now.saturating_duration_since(*inserted) < self.window {
+                break;
+            }
+            if let Some((_, client_id, client_order_id)) = self.expiry.pop_front() {
+                self.seen.remove(&(client_id, client_order_id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_within_window() {
+        let mut ids = RecentOrderIds::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert!(ids.insert(start, ClientId(1), "abc"));
+        assert!(!ids.insert(start, ClientId(1), "abc"));
+        // Ids are scoped per client
+        assert!(ids.insert(start, ClientId(2), "abc"));
+
+        assert!(ids.insert(start + Duration::from_secs(10), ClientId(1), "abc"));
+    }
+}
@@ -12,8 +12,20 @@
     // tokio::select !{} does this internally...
     clippy::redundant_pub_crate
 )]
+pub mod clock;
+pub mod codec;
+pub mod config;
+pub mod credit;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
 pub mod decoder;
 pub mod encoder;
+pub mod idempotency;
 pub mod matcher;
 pub mod models;
+pub mod products;
+pub mod proxy;
+pub mod rate_limit;
 pub mod server;
+pub mod shutdown;
+pub mod sink;
@@ -12,18 +12,74 @@
     // tokio::select !{} does this internally...
     clippy::redundant_pub_crate
 )]
+use clap::{Parser, ValueEnum};
+use single_thread_async_server::config::Config;
 use single_thread_async_server::decoder::{Decoder, DecoderEvent, DecoderTaskControl};
 use single_thread_async_server::encoder::{Encoder, EncoderTaskControl};
 use single_thread_async_server::server::Server;
+use single_thread_async_server::shutdown;
+use std::path::PathBuf;
 use tokio_util::sync::CancellationToken;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Format of the log lines written to stdout.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including the fields of the enclosing spans
+    Json,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// TOML file with the server settings. The options below override it
+    #[arg(long, env = "SERVER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0:8888]
+    #[arg(long, env = "SERVER_BIND")]
+    bind: Option<String>,
+    /// Close connections beyond this many concurrent clients
+    #[arg(long, env = "SERVER_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+    #[arg(long, env = "SERVER_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Address to stream server events to WebSocket dashboards on
+    #[cfg(feature = "dashboard")]
+    #[arg(long, env = "SERVER_DASHBOARD_BIND")]
+    dashboard_bind: Option<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+    }
+
+    tracing::info!("Starting with {args:?}");
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(run(args))
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(bind) = args.bind {
+        config.bind = bind;
+    }
+    if args.max_connections.is_some() {
+        config.max_connections = args.max_connections;
+    }
 
-    let mut server = Server::bind("0.0.0.0:8888").await?;
-    let mut encoder = Encoder::default();
-    let mut decoder = Decoder::default();
+    let mut server = Server::from_config(&config).await?;
+    let mut encoder = Encoder::new(config.encoder_config());
+    let mut decoder = Decoder::new(config.decoder_config());
 
     let (encoder_sender, encoder_receiver) =
         tokio::sync::mpsc::channel::<EncoderTaskControl>(u8::MAX as usize);
@@ -32,45 +88,76 @@ async fn main() -> anyhow::Result<()> {
     let (decoder_event_sender, decoder_event_receiver) =
         tokio::sync::mpsc::channel::<DecoderEvent>(u8::MAX as usize);
 
-    let cancellation_token = CancellationToken::new();
-    let ctrlc_cancellation_token = cancellation_token.clone();
+    let cancellation_token = shutdown::listen()?;
 
-    let encoder_fut = encoder.run(encoder_receiver);
-    let decoder_fut = decoder.run(decoder_receiver, decoder_event_sender);
-    let server_fut = server.run(
-        encoder_sender,
-        decoder_sender,
-        decoder_event_receiver,
-        cancellation_token.clone(),
-    );
+    #[cfg(feature = "dashboard")]
+    if let Some(bind) = &args.dashboard_bind {
+        let (events, _) = tokio::sync::broadcast::channel(u8::MAX as usize);
+        server = server.with_events(events.clone());
+        let listener = tokio::net::TcpListener::bind(bind.as_str()).await?;
+        let dashboard_cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            let result = single_thread_async_server::dashboard::serve(
+                listener,
+                events,
+                dashboard_cancellation_token,
+            )
+            .await;
+            if let Err(e) = result {
+                tracing::error!("Dashboard error: {e:?}");
+            }
+        });
+    }
+    let encoder_flush_token = CancellationToken::new();
 
-    ctrlc::set_handler(move || {
-        tracing::warn!("Ctrl-C received, cancelling tasks");
-        ctrlc_cancellation_token.cancel();
-    })
-    .expect("Error setting Ctrl-C handler");
+    let encoder_flush_token_clone = encoder_flush_token.clone();
+    let encoder_fut = tokio::spawn(async move {
+        encoder
+            .run(encoder_receiver, encoder_flush_token_clone)
+            .await
+    });
+    let decoder_fut =
+        tokio::spawn(async move { decoder.run(decoder_receiver, decoder_event_sender).await });
+    let server_cancellation_token = cancellation_token.clone();
+    let server_fut = tokio::spawn(async move {
+        server
+            .run(
+                encoder_sender,
+                decoder_sender,
+                decoder_event_receiver,
+                server_cancellation_token,
+                encoder_flush_token,
+            )
+            .await
+    });
 
-    tokio::select! {
-        server = server_fut => {
-            match server {
-                Ok(()) => {
-                    tracing::info!("Server finished gracefully. Not cancelling, dependent tasks already terminate if they lose their input");
-                }
-                Err(e) => {
-                    tracing::error!("Server error: {e:?}");
-                    cancellation_token.cancel();
-                }
-            }
-        }
-        result = encoder_fut => {
-            tracing::warn!("Encoder finished: {result:?}");
+    // Any task finishing cancels the server, which then sequences the shutdown of the others:
+    // the server stops accepting and closes the decoder, the decoder exits, the server handles
+    // the decoder events still in flight, and last the encoder flushes and exits. Each step is a
+    // clean exit, so anything logged as an error here is a real failure. All three are awaited
+    // so the encoder gets to flush before we exit.
+    tokio::join!(
+        async {
+            log_task_result("Server", server_fut.await);
             cancellation_token.cancel();
-        }
-        result = decoder_fut => {
-            tracing::warn!("Decoder finished: {result:?}");
+        },
+        async {
+            log_task_result("Encoder", encoder_fut.await);
             cancellation_token.cancel();
-        }
-    };
+        },
+        async {
+            log_task_result("Decoder", decoder_fut.await);
+            cancellation_token.cancel();
+        },
+    );
 
     Ok(())
 }
+
+fn log_task_result(task: &str, result: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => tracing::info!("{task} finished gracefully"),
+        Ok(Err(e)) => tracing::error!("{task} error: {e:?}"),
+        Err(e) => tracing::error!("{task} task failed: {e:?}"),
+    }
+}
@@ -1,19 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use crate::models::Product;
+use crate::models::{Order, Product, Side, TapeEntry, VenueId};
 
-#[derive(Debug)]
+/// Trades kept per product for `TAPE` requests.
+const DEFAULT_TAPE_LEN: usize = 100;
+
+#[derive(Debug, Default)]
 pub struct OrderCount(pub u32);
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Book {
     pub buys: OrderCount,
     pub sells: OrderCount,
 }
 
-#[derive(Debug)]
-pub struct Matcher {
-    pub books: HashMap<Product, Book>,
+impl Book {
+    /// `(bids - asks) / (bids + asks)`, from -1 (only sells) to 1 (only buys). An empty book is
+    /// balanced.
+    #[must_use]
+    pub fn imbalance(&self) -> f64 {
+        let bids = f64::from(self.buys.0);
+        let asks = f64::from(self.sells.0);
+        if bids + asks == 0.0 {
+            return 0.0;
+        }
+
+        (bids - asks) / (bids + asks)
+    }
+}
+
+/// Resting orders per side of a book at one point in time, e.g. for a depth chart. Books are
+/// count based, so there are no price levels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BookSnapshot {
+    pub buys: u32,
+    pub sells: u32,
 }
 
 #[derive(Debug)]
@@ -21,39 +42,560 @@ pub struct Match {
     pub product: Product,
 }
 
+/// Matching rules for the book of a single product.
+pub trait MatchingEngine: std::fmt::Debug + Send + Sync {
+    fn add_order(&mut self, order: &Order) -> Option<Match>;
+
+    /// Adds a resting order without matching it against the book.
+    fn seed(&mut self, side: Side);
+
+    /// Drops every resting order, returning how many there were.
+    fn clear(&mut self) -> u32;
+
+    /// Whether an order on `side` would match rather than rest. Engines that do not track resting
+    /// orders may always say yes, their orders are then never refused for a full book.
+    fn would_match(&self, _side: Side) -> bool {
+        true
+    }
+
+    /// Imbalance of the resting orders, see `Book::imbalance`. `None` if the engine does not
+    /// track resting orders.
+    fn imbalance(&self) -> Option<f64> {
+        None
+    }
+
+    /// The resting orders, `None` if the engine does not track them.
+    fn snapshot(&self) -> Option<BookSnapshot> {
+        None
+    }
+}
+
+/// Matches any buy against any sell, in arrival order. This is the default engine.
+#[derive(Debug, Default)]
+pub struct CountEngine {
+    pub book: Book,
+}
+
+impl MatchingEngine for CountEngine {
+    fn add_order(&mut self, order: &Order) -> Option<Match> {
+        let (resting, opposite) = match order.side {
+            Side::Buy => (&mut self.book.buys, &mut self.book.sells),
+            Side::Sell => (&mut self.book.sells, &mut self.book.buys),
+        };
+
+        if opposite.0 > 0 {
+            opposite.0 -= 1;
+            Some(Match {
+                product: order.product,
+            })
+        } else {
+            resting.0 += 1;
+            None
+        }
+    }
+
+    fn seed(&mut self, side: Side) {
+        match side {
+            Side::Buy => self.book.buys.0 += 1,
+            Side::Sell => self.book.sells.0 += 1,
+        }
+    }
+
+    fn clear(&mut self) -> u32 {
+        let Book { buys, sells } = std::mem::take(&mut self.book);
+        buys.0 + sells.0
+    }
+
+    fn would_match(&self, side: Side) -> bool {
+        let opposite = match side {
+            Side::Buy => &self.book.sells,
+            Side::Sell => &self.book.buys,
+        };
+        opposite.0 > 0
+    }
+
+    fn imbalance(&self) -> Option<f64> {
+        Some(self.book.imbalance())
+    }
+
+    fn snapshot(&self) -> Option<BookSnapshot> {
+        Some(BookSnapshot {
+            buys: self.book.buys.0,
+            sells: self.book.sells.0,
+        })
+    }
+}
+
+/// Identifies a book: the venue, `None` for the default one, and the product traded in it.
+type BookKey = (Option<VenueId>, Product);
+
+#[derive(Debug)]
+pub struct Matcher {
+    engines: HashMap<BookKey, Box<dyn MatchingEngine>>,
+    // The most recent trades per product, oldest first
+    tapes: HashMap<Product, VecDeque<TapeEntry>>,
+    tape_len: usize,
+    next_trade_id: u64,
+    max_resting_orders: Option<u32>,
+    // Across all books
+    resting_orders: u32,
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Matcher {
+    #[must_use]
     pub fn new() -> Self {
         Self {
-            books: HashMap::new(),
+            engines: HashMap::new(),
+            tapes: HashMap::new(),
+            tape_len: DEFAULT_TAPE_LEN,
+            next_trade_id: 1,
+            max_resting_orders: None,
+            resting_orders: 0,
         }
     }
 
-    fn get_book(&mut self, product: Product) -> &mut Book {
-        self.books.entry(product).or_insert_with(|| Book {
-            buys: OrderCount(0),
-            sells: OrderCount(0),
+    /// Keeps the last `tape_len` trades per product instead of the default 100.
+    #[must_use]
+    pub const fn with_tape_len(mut self, tape_len: usize) -> Self {
+        self.tape_len = tape_len;
+        self
+    }
+
+    /// Caps the resting orders across all books, see `is_book_full`.
+    #[must_use]
+    pub const fn with_max_resting_orders(mut self, max_resting_orders: u32) -> Self {
+        self.max_resting_orders = Some(max_resting_orders);
+        self
+    }
+
+    /// Routes orders for `product` on the default venue to `engine` instead of a `CountEngine`.
+    #[must_use]
+    pub fn with_engine(mut self, product: Product, engine: Box<dyn MatchingEngine>) -> Self {
+        self.engines.insert((None, product), engine);
+        self
+    }
+
+    fn get_engine(&mut self, venue: Option<VenueId>, product: Product) -> &mut dyn MatchingEngine {
+        self.engines
+            .entry((venue, product))
+            .or_insert_with(|| Box::new(CountEngine::default()))
+            .as_mut()
+    }
+
+    /// Whether `order` has to be refused because it would rest while the books already hold
+    /// `max_resting_orders`. Orders that match are always taken. Callers check this before
+    /// `add_order`, which does not enforce the cap.
+    #[must_use]
+    pub fn is_book_full(&self, order: &Order) -> bool {
+        self.max_resting_orders
+            .is_some_and(|max| self.resting_orders >= max)
+            && self
+                .engines
+                .get(&(order.venue, order.product))
+                .is_none_or(|engine| !engine.would_match(order.side))
+    }
+
+    /// Matches `order` against the book of its venue or rests it there. Returns the trade as
+    /// recorded on the tape if it matched. The tape is per product, across venues.
+    pub fn add_order(&mut self, order: &Order) -> Option<TapeEntry> {
+        let Some(trade) = self.get_engine(order.venue, order.product).add_order(order) else {
+            self.resting_orders += 1;
+            debug_assert_eq!(self.check_invariants(), Ok(()));
+            return None;
+        };
+        self.resting_orders = self.resting_orders.saturating_sub(1);
+        let entry = self.record_trade(trade.product, order.side);
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+
+        Some(entry)
+    }
+
+    fn record_trade(&mut self, product: Product, side: Side) -> TapeEntry {
+        let entry = TapeEntry {
+            trade_id: self.next_trade_id,
+            side,
+        };
+        self.next_trade_id += 1;
+
+        let tape = self.tapes.entry(product).or_default();
+        if tape.len() >= self.tape_len {
+            tape.pop_front();
+        }
+        if self.tape_len > 0 {
+            tape.push_back(entry);
+        }
+
+        entry
+    }
+
+    /// Up to the last `count` trades for `product`, oldest first.
+    #[must_use]
+    pub fn tape(&self, product: Product, count: usize) -> Vec<TapeEntry> {
+        self.tapes.get(&product).map_or_else(Vec::new, |tape| {
+            tape.iter()
+                .skip(tape.len().saturating_sub(count))
+                .copied()
+                .collect()
         })
     }
 
-    pub fn add_buy(&mut self, product: Product) -> Option<Match> {
-        let book = self.get_book(product);
-        if book.sells.0 > 0 {
-            book.sells.0 -= 1;
-            Some(Match { product })
-        } else {
-            book.buys.0 += 1;
-            None
+    /// Imbalance of the `product` book on the default venue.
+    #[must_use]
+    pub fn imbalance(&self, product: Product) -> Option<f64> {
+        self.engines
+            .get(&(None, product))
+            .and_then(|engine| engine.imbalance())
+    }
+
+    /// The resting orders for `product` on the default venue, `None` if nothing was traded in it
+    /// yet or its engine does not track them.
+    #[must_use]
+    pub fn book_snapshot(&self, product: Product) -> Option<BookSnapshot> {
+        self.engines
+            .get(&(None, product))
+            .and_then(|engine| engine.snapshot())
+    }
+
+    /// The `product` book on the default venue as a depth document for dashboards, e.g.
+    /// `{"product": "APPLE", "bids": [{"orders": 3}], "asks": []}`. Books are count based, so
+    /// each side has at most one level, without a price. Unknown books are empty.
+    #[cfg(feature = "serde")]
+    #[must_use]
+    pub fn to_depth_json(&self, product: Product) -> serde_json::Value {
+        let book = self.book_snapshot(product).unwrap_or_default();
+        let levels = |orders: u32| {
+            if orders == 0 {
+                Vec::new()
+            } else {
+                vec![serde_json::json!({ "orders": orders })]
+            }
+        };
+
+        serde_json::json!({
+            "product": product.as_str(),
+            "bids": levels(book.buys),
+            "asks": levels(book.sells),
+        })
+    }
+
+    /// Loads resting orders directly into the default venue's books, e.g. to restore state at
+    /// startup. Nothing is matched, so no trades or acks result from seeding.
+    pub fn seed(&mut self, orders: impl IntoIterator<Item = (Side, Product)>) {
+        for (side, product) in orders {
+            self.get_engine(None, product).seed(side);
+            self.resting_orders += 1;
         }
+        debug_assert_eq!(self.check_invariants(), Ok(()));
     }
 
-    pub fn add_sell(&mut self, product: Product) -> Option<Match> {
-        let book = self.get_book(product);
-        if book.buys.0 > 0 {
-            book.buys.0 -= 1;
-            Some(Match { product })
-        } else {
-            book.sells.0 += 1;
-            None
+    /// Cancels every resting order for `product` on every venue, e.g. at the end of a session.
+    /// Returns how many orders were cancelled.
+    pub fn clear_book(&mut self, product: Product) -> u32 {
+        let cleared = self
+            .engines
+            .iter_mut()
+            .filter(|((_, book_product), _)| *book_product == product)
+            .map(|(_, engine)| engine.clear())
+            .sum();
+        self.resting_orders = self.resting_orders.saturating_sub(cleared);
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+
+        cleared
+    }
+
+    /// Checks the bookkeeping across books, describing the first inconsistency found. The resting
+    /// order count has to match the books, and every tape has to be within its length with trade
+    /// ids increasing. Checked after every change in debug builds.
+    ///
+    /// Books are count based, where any buy matches any sell, so there is no crossing to check:
+    /// seeding both sides of a book is allowed.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        // Only comparable if every engine tracks its resting orders
+        let books: Option<Vec<_>> = self.engines.values().map(|e| e.snapshot()).collect();
+        if let Some(books) = books {
+            let resting: u32 = books.iter().map(|book| book.buys + book.sells).sum();
+            if resting != self.resting_orders {
+                return Err(format!(
+                    "{} resting orders counted, but the books hold {resting}",
+                    self.resting_orders
+                ));
+            }
+        }
+
+        for (product, tape) in &self.tapes {
+            if tape.len() > self.tape_len {
+                return Err(format!(
+                    "{product} tape holds {} trades, more than {}",
+                    tape.len(),
+                    self.tape_len
+                ));
+            }
+            let mut trade_ids = tape.iter().map(|entry| entry.trade_id);
+            let in_order = trade_ids
+                .clone()
+                .zip(trade_ids.clone().skip(1))
+                .all(|(previous, next)| previous < next);
+            if !in_order || trade_ids.any(|trade_id| trade_id >= self.next_trade_id) {
+                return Err(format!("{product} tape is out of order: {tape:?}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    /// Stand-in for a price engine: crosses every order and counts what it saw.
+    #[derive(Debug, Default)]
+    struct MockPriceEngine {
+        orders: Arc<AtomicUsize>,
+    }
+
+    impl MatchingEngine for MockPriceEngine {
+        fn add_order(&mut self, order: &Order) -> Option<Match> {
+            self.orders.fetch_add(1, Ordering::Relaxed);
+            Some(Match {
+                product: order.product,
+            })
+        }
+
+        fn seed(&mut self, _side: Side) {}
+
+        fn clear(&mut self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_orders_route_to_product_engine() {
+        let pear_orders = Arc::new(AtomicUsize::new(0));
+        let mut matcher = Matcher::new()
+            .with_engine(Product::Apples, Box::new(CountEngine::default()))
+            .with_engine(
+                Product::Pears,
+                Box::new(MockPriceEngine {
+                    orders: pear_orders.clone(),
+                }),
+            );
+
+        // Count engine: the first buy rests
+        assert!(matcher.add_order(&"BUY:APPLE".parse().unwrap()).is_none());
+        // Mock engine: every order matches
+        assert!(matcher.add_order(&"BUY:PEAR".parse().unwrap()).is_some());
+        assert!(matcher.add_order(&"SELL:APPLE".parse().unwrap()).is_some());
+
+        assert_eq!(pear_orders.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_unconfigured_product_uses_count_engine() {
+        let mut matcher = Matcher::new();
+
+        assert!(matcher.add_order(&"SELL:ONION".parse().unwrap()).is_none());
+        assert!(matcher.add_order(&"BUY:ONION".parse().unwrap()).is_some());
+        assert!(matcher.add_order(&"BUY:ONION".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_seeded_orders_rest_without_matching() {
+        let mut matcher = Matcher::new();
+
+        // Crossing orders are not matched against each other while seeding
+        matcher.seed([(Side::Buy, Product::Apples), (Side::Sell, Product::Apples)]);
+        matcher.seed(std::iter::repeat_n((Side::Sell, Product::Pears), 5));
+
+        for _ in 0..5 {
+            assert!(matcher.add_order(&"BUY:PEAR".parse().unwrap()).is_some());
         }
+        assert!(matcher.add_order(&"BUY:PEAR".parse().unwrap()).is_none());
+
+        assert!(matcher.add_order(&"SELL:APPLE".parse().unwrap()).is_some());
+        assert!(matcher.add_order(&"BUY:APPLE".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_clear_book_cancels_resting_orders() {
+        let mut matcher = Matcher::new();
+        matcher.seed(std::iter::repeat_n((Side::Sell, Product::Apples), 3));
+        matcher.seed([(Side::Buy, Product::Pears)]);
+        assert!(matcher.add_order(&"BUY:APPLE".parse().unwrap()).is_some());
+
+        assert_eq!(matcher.clear_book(Product::Apples), 2);
+        assert_eq!(matcher.clear_book(Product::Onions), 0);
+        assert_eq!(matcher.imbalance(Product::Apples), Some(0.0));
+
+        // Nothing left to cross, while other books are untouched
+        assert!(matcher.add_order(&"BUY:APPLE".parse().unwrap()).is_none());
+        assert!(matcher.add_order(&"SELL:PEAR".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_book_snapshot() {
+        let mut matcher =
+            Matcher::new().with_engine(Product::Pears, Box::<MockPriceEngine>::default());
+        matcher.seed(std::iter::repeat_n((Side::Buy, Product::Apples), 3));
+        matcher.seed([(Side::Sell, Product::Apples)]);
+        assert!(matcher.add_order(&"SELL:APPLE".parse().unwrap()).is_some());
+        assert!(matcher.add_order(&"BUY:PEAR".parse().unwrap()).is_some());
+
+        assert_eq!(
+            matcher.book_snapshot(Product::Apples),
+            Some(BookSnapshot { buys: 2, sells: 1 })
+        );
+        assert_eq!(matcher.book_snapshot(Product::Pears), None);
+        assert_eq!(matcher.book_snapshot(Product::Onions), None);
+    }
+
+    #[test]
+    fn test_check_invariants_flags_broken_bookkeeping() {
+        let mut matcher = Matcher::new();
+        matcher.seed([(Side::Buy, Product::Apples), (Side::Sell, Product::Apples)]);
+        assert!(matcher.add_order(&"BUY:PEAR".parse().unwrap()).is_none());
+        assert!(matcher.add_order(&"SELL:PEAR".parse().unwrap()).is_some());
+        assert_eq!(matcher.check_invariants(), Ok(()));
+
+        // Clearing the engine directly skips the resting order count
+        matcher
+            .engines
+            .get_mut(&(None, Product::Apples))
+            .unwrap()
+            .clear();
+        let error = matcher.check_invariants().unwrap_err();
+        assert!(error.contains("2 resting orders counted"), "{error}");
+        matcher.resting_orders = 0;
+
+        matcher
+            .tapes
+            .get_mut(&Product::Pears)
+            .unwrap()
+            .push_back(TapeEntry {
+                trade_id: 1,
+                side: Side::Buy,
+            });
+        let error = matcher.check_invariants().unwrap_err();
+        assert!(error.contains("PEAR tape is out of order"), "{error}");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "resting orders counted")]
+    fn test_broken_bookkeeping_panics_in_debug_builds() {
+        let mut matcher = Matcher::new();
+        matcher.seed([(Side::Buy, Product::Apples)]);
+        matcher
+            .engines
+            .get_mut(&(None, Product::Apples))
+            .unwrap()
+            .clear();
+
+        matcher.add_order(&"BUY:PEAR".parse().unwrap());
+    }
+
+    #[test]
+    fn test_tape_keeps_the_most_recent_trades() {
+        let mut matcher = Matcher::new().with_tape_len(2);
+        for order in ["BUY:APPLE", "SELL:APPLE", "SELL:PEAR", "BUY:PEAR"] {
+            matcher.add_order(&order.parse().unwrap());
+        }
+        for order in ["SELL:APPLE", "BUY:APPLE", "BUY:APPLE", "SELL:APPLE"] {
+            matcher.add_order(&order.parse().unwrap());
+        }
+
+        let side_and_id = |entry: &TapeEntry| (entry.side, entry.trade_id);
+        let apples: Vec<_> = matcher
+            .tape(Product::Apples, 5)
+            .iter()
+            .map(side_and_id)
+            .collect();
+        assert_eq!(apples, [(Side::Buy, 3), (Side::Sell, 4)]);
+        let pears: Vec<_> = matcher
+            .tape(Product::Pears, 5)
+            .iter()
+            .map(side_and_id)
+            .collect();
+        assert_eq!(pears, [(Side::Buy, 2)]);
+        assert_eq!(matcher.tape(Product::Apples, 1)[0].trade_id, 4);
+        assert!(matcher.tape(Product::Onions, 5).is_empty());
+    }
+
+    #[test]
+    fn test_count_engine_imbalance() {
+        let mut matcher = Matcher::new();
+        assert_eq!(matcher.imbalance(Product::Apples), None);
+
+        matcher.seed([
+            (Side::Buy, Product::Apples),
+            (Side::Buy, Product::Apples),
+            (Side::Buy, Product::Apples),
+            (Side::Sell, Product::Apples),
+        ]);
+        assert_eq!(matcher.imbalance(Product::Apples), Some(0.5));
+
+        let sell = Order {
+            side: Side::Sell,
+            product: Product::Apples,
+            client_order_id: None,
+            venue: None,
+        };
+        for _ in 0..3 {
+            matcher.add_order(&sell);
+        }
+        assert_eq!(matcher.imbalance(Product::Apples), Some(-1.0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_depth_json() {
+        let mut matcher = Matcher::new();
+        matcher.seed(std::iter::repeat_n((Side::Buy, Product::Apples), 3));
+
+        assert_eq!(
+            matcher.to_depth_json(Product::Apples),
+            serde_json::json!({
+                "product": "APPLE",
+                "bids": [{ "orders": 3 }],
+                "asks": [],
+            })
+        );
+        assert_eq!(
+            matcher.to_depth_json(Product::Onions)["bids"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn test_venues_have_separate_books() {
+        let mut matcher = Matcher::new();
+        let order = |side, venue: &str| Order {
+            side,
+            product: Product::Apples,
+            client_order_id: None,
+            venue: Some(venue.parse().unwrap()),
+        };
+
+        assert!(matcher.add_order(&order(Side::Buy, "B")).is_none());
+        assert!(matcher.add_order(&order(Side::Sell, "A")).is_none());
+        // Neither rests on the default venue
+        assert_eq!(matcher.book_snapshot(Product::Apples), None);
+
+        assert!(matcher.add_order(&order(Side::Buy, "A")).is_some());
+        assert!(matcher.add_order(&order(Side::Sell, "B")).is_some());
+        assert_eq!(matcher.tape(Product::Apples, 5).len(), 2);
+        assert_eq!(matcher.clear_book(Product::Apples), 0);
     }
 }
@@ -1,9 +1,82 @@
-use std::{io::Write, str::FromStr};
+use std::{io::Write, num::NonZeroUsize, str::FromStr, time::Duration};
 
 use anyhow::Context;
 
 const NEWLINE: u8 = b'\n';
 const NEWLINE_ARRAY: [u8; 1] = [NEWLINE];
+/// Marks a subscription whose imbalance updates are conflated, `SUB:<product>@conflate`.
+const CONFLATE_SUFFIX: &str = "@conflate";
+
+/// Upper bound on `@clid=` values so acks always fit the encoder buffer.
+pub const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+
+/// Longest product name accepted outside of the built-in products.
+pub const MAX_SYMBOL_LEN: usize = 16;
+
+/// Name of a product outside the built-in set, stored inline so `Product` stays `Copy`.
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
+pub struct Symbol {
+    bytes: [u8; MAX_SYMBOL_LEN],
+    len: u8,
+}
+
+impl Symbol {
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only ever constructed from validated ASCII
+        std::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Symbol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        anyhow::ensure!(
+            !s.is_empty() && s.len() <= MAX_SYMBOL_LEN,
+            "Product name must be 1 to {MAX_SYMBOL_LEN} characters: {s}",
+        );
+        anyhow::ensure!(
+            s.bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_'),
+            "Invalid product name: {s}",
+        );
+
+        let mut bytes = [0; MAX_SYMBOL_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+
+        Ok(Self {
+            bytes,
+            len: u8::try_from(s.len())?,
+        })
+    }
+}
+
+/// A venue orders can be sent to with `@venue=<name>`, each with its own books. Orders without
+/// one go to the default venue. Names follow the rules for product names.
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
+pub struct VenueId(Symbol);
+
+impl VenueId {
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl FromStr for VenueId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse().context("Invalid venue")?))
+    }
+}
+
+impl std::fmt::Display for VenueId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
 pub enum Product {
@@ -12,6 +85,56 @@ pub enum Product {
     Tomatoes,
     Potatoes,
     Onions,
+    /// Any other well-formed name. Whether it can be traded depends on the product registry.
+    Other(Symbol),
+}
+
+impl Product {
+    pub const BUILTIN: [Self; 5] = [
+        Self::Apples,
+        Self::Pears,
+        Self::Tomatoes,
+        Self::Potatoes,
+        Self::Onions,
+    ];
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Apples => "APPLE",
+            Self::Pears => "PEAR",
+            Self::Tomatoes => "TOMATO",
+            Self::Potatoes => "POTATO",
+            Self::Onions => "ONION",
+            Self::Other(symbol) => symbol.as_str(),
+        }
+    }
+}
+
+/// How product names in requests are matched, see `DecoderConfig::product_case`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProductCase {
+    /// Only uppercase names, `APPLE`.
+    #[default]
+    Sensitive,
+    /// Names in any case, `apple` and `Apple` are `APPLE` too.
+    Insensitive,
+}
+
+impl Product {
+    /// Parses `s` like `from_str`, uppercasing it first with `ProductCase::Insensitive`. The
+    /// uppercase copy is kept on the stack, names longer than that are invalid anyway.
+    pub fn parse_with_case(s: &str, case: ProductCase) -> anyhow::Result<Self> {
+        if case == ProductCase::Sensitive || s.len() > MAX_SYMBOL_LEN {
+            return s.parse();
+        }
+        let mut bytes = [0; MAX_SYMBOL_LEN];
+        let uppercase = &mut bytes[..s.len()];
+        uppercase.copy_from_slice(s.as_bytes());
+        uppercase.make_ascii_uppercase();
+
+        std::str::from_utf8(uppercase)?.parse()
+    }
 }
 
 impl FromStr for Product {
@@ -24,22 +147,14 @@ impl FromStr for Product {
             "TOMATO" => Ok(Self::Tomatoes),
             "POTATO" => Ok(Self::Potatoes),
             "ONION" => Ok(Self::Onions),
-            other => {
-                anyhow::bail!("Unknown product: {other}");
-            }
+            other => Ok(Self::Other(other.parse()?)),
         }
     }
 }
 
-impl ToString for Product {
-    fn to_string(&self) -> String {
-        match self {
-            Self::Apples => "APPLE".to_string(),
-            Self::Pears => "PEAR".to_string(),
-            Self::Tomatoes => "TOMATO".to_string(),
-            Self::Potatoes => "POTATO".to_string(),
-            Self::Onions => "ONION".to_string(),
-        }
+impl std::fmt::Display for Product {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -49,6 +164,16 @@ pub enum Side {
     Sell,
 }
 
+impl Side {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Buy => "BUY",
+            Self::Sell => "SELL",
+        }
+    }
+}
+
 impl FromStr for Side {
     type Err = anyhow::Error;
 
@@ -63,29 +188,77 @@ impl FromStr for Side {
     }
 }
 
+/// Identifies a connected client in frames and between the tasks. Build it with `From<u16>`,
+/// and format it with `Display`.
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
 pub struct ClientId(pub u16);
 
+impl From<u16> for ClientId {
+    fn from(id: u16) -> Self {
+        Self(id)
+    }
+}
+
+impl ClientId {
+    /// Length of the id as formatted by `Display`.
+    const fn display_len(self) -> usize {
+        match self.0.checked_ilog10() {
+            Some(digits) => digits as usize + 1,
+            None => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct Login {
     pub client_id: ClientId,
 }
 
-pub trait Encode: Send + std::fmt::Debug {
+/// Smallest scratch buffer tried by the default `Encode::encoded_len`.
+const MIN_SCRATCH_LEN: usize = 64;
+
+pub trait Encode: Send + Sync + std::fmt::Debug {
+    /// Writes the frame to `buffer`, returning its length. A frame longer than `buffer` is cut
+    /// short.
     fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize>;
+
+    /// Length of the frame `encode` writes, e.g. to size its buffer exactly. The default encodes
+    /// into scratch buffers until one has room to spare, frames on hot paths work it out
+    /// directly.
+    fn encoded_len(&self) -> usize {
+        let mut scratch = vec![0; MIN_SCRATCH_LEN];
+        loop {
+            match self.encode(&mut scratch) {
+                Ok(length) if length < scratch.len() => return length,
+                Ok(_) => scratch.resize(scratch.len() * 2, 0),
+                // Nothing is written for frames that fail to encode
+                Err(_) => return 0,
+            }
+        }
+    }
 }
 
 impl Encode for Login {
     fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
         let mut length = 0;
         length += (&mut buffer[length..]).write(b"LOGIN:")?;
-        length += (&mut buffer[length..]).write(self.client_id.0.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(self.client_id.to_string().as_bytes())?;
         length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
 
         tracing::debug!("Login encoded: {:?}", &buffer[..length]);
 
         Ok(length)
     }
+
+    fn encoded_len(&self) -> usize {
+        b"LOGIN:".len() + self.client_id.display_len() + NEWLINE_ARRAY.len()
+    }
 }
 
 #[derive(Debug)]
@@ -98,7 +271,7 @@ impl Encode for Message {
     fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
         let mut length = 0;
         length += (&mut buffer[length..]).write(b"MESSAGE:")?;
-        length += (&mut buffer[length..]).write(self.origin_client_id.0.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(self.origin_client_id.to_string().as_bytes())?;
         length += (&mut buffer[length..]).write(b" ")?;
         length += (&mut buffer[length..]).write(self.message.as_bytes())?;
         length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
@@ -107,6 +280,14 @@ impl Encode for Message {
 
         Ok(length)
     }
+
+    fn encoded_len(&self) -> usize {
+        b"MESSAGE:".len()
+            + self.origin_client_id.display_len()
+            + b" ".len()
+            + self.message.len()
+            + NEWLINE_ARRAY.len()
+    }
 }
 
 #[derive(Debug)]
@@ -122,66 +303,839 @@ impl Encode for MessageAck {
     }
 }
 
+/// Reply to `OPTS:compress`, the last frame before the client's deflate stream starts.
+#[derive(Debug)]
+pub struct CompressionAck;
+
+impl Encode for CompressionAck {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let length = (&mut buffer[0..]).write(b"ACK:COMPRESS\n")?;
+
+        tracing::debug!("CompressionAck encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Reply to `PING`.
+#[derive(Debug)]
+pub struct Pong;
+
+impl Encode for Pong {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let length = (&mut buffer[0..]).write(b"PONG\n")?;
+
+        tracing::debug!("Pong encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Reply to `QDEPTH`: the bytes queued for the client that were not written yet.
+#[derive(Debug)]
+pub struct QueueDepth {
+    pub depth: usize,
+}
+
+impl Encode for QueueDepth {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // QDEPTH:{depth}
+        length += (&mut buffer[length..]).write(b"QDEPTH:")?;
+        length += (&mut buffer[length..]).write(self.depth.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("QueueDepth encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Confirms a `FOLLOW:<client_id>`.
+#[derive(Debug)]
+pub struct FollowAck {
+    pub client_id: ClientId,
+}
+
+impl Encode for FollowAck {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // ACK:FOLLOW:{client_id}
+        length += (&mut buffer[length..]).write(b"ACK:FOLLOW:")?;
+        length += (&mut buffer[length..]).write(self.client_id.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("FollowAck encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+
+    fn encoded_len(&self) -> usize {
+        b"ACK:FOLLOW:".len() + self.client_id.display_len() + NEWLINE_ARRAY.len()
+    }
+}
+
+/// Sent to a client's followers when its order completes a trade.
+#[derive(Debug)]
+pub struct FollowedTrade {
+    pub client_id: ClientId,
+    pub product: Product,
+}
+
+impl Encode for FollowedTrade {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // FOLLOWED_TRADE:{client_id}:{product}
+        length += (&mut buffer[length..]).write(b"FOLLOWED_TRADE:")?;
+        length += (&mut buffer[length..]).write(self.client_id.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(b":")?;
+        length += (&mut buffer[length..]).write(self.product.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("FollowedTrade encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+
+    fn encoded_len(&self) -> usize {
+        b"FOLLOWED_TRADE:".len()
+            + self.client_id.display_len()
+            + b":".len()
+            + self.product.as_str().len()
+            + NEWLINE_ARRAY.len()
+    }
+}
+
+/// Sent to clients right before the server disconnects them at the end of a drain.
+#[derive(Debug)]
+pub struct Bye;
+
+impl Encode for Bye {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let length = (&mut buffer[0..]).write(b"BYE\n")?;
+
+        tracing::debug!("Bye encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Sent to clients that have been idle long enough to be disconnected soon, unless they send
+/// something first.
+#[derive(Debug)]
+pub struct IdleWarning;
+
+impl Encode for IdleWarning {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let length = (&mut buffer[0..]).write(b"WARN:IDLE\n")?;
+
+        tracing::debug!("IdleWarning encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// What a `SUB:` or `UNSUB:` request is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subscription {
+    /// `*`, every product, including the ones registered later.
+    AllProducts,
+    Product(Product),
+}
+
+impl FromStr for Subscription {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_case(s, ProductCase::Sensitive)
+    }
+}
+
+impl Subscription {
+    /// Parses `s` like `from_str`, matching the product name as `case` says.
+    pub fn parse_with_case(s: &str, case: ProductCase) -> anyhow::Result<Self> {
+        if s == "*" {
+            return Ok(Self::AllProducts);
+        }
+
+        Ok(Self::Product(Product::parse_with_case(s, case)?))
+    }
+}
+
+impl std::fmt::Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AllProducts => f.write_str("*"),
+            Self::Product(product) => product.fmt(f),
+        }
+    }
+}
+
+/// Confirms a `SUB:<product>` or `UNSUB:<product>`.
+#[derive(Debug)]
+pub struct SubscriptionAck {
+    pub subscription: Subscription,
+    pub subscribed: bool,
+    /// Whether the subscription was made with `@conflate`.
+    pub conflate: bool,
+}
+
+impl Encode for SubscriptionAck {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // ACK:{SUB|UNSUB}:{product}[@conflate]
+        let kind: &[u8] = if self.subscribed {
+            b"ACK:SUB:"
+        } else {
+            b"ACK:UNSUB:"
+        };
+        length += (&mut buffer[length..]).write(kind)?;
+        length += (&mut buffer[length..]).write(self.subscription.to_string().as_bytes())?;
+        if self.conflate {
+            length += (&mut buffer[length..]).write(CONFLATE_SUFFIX.as_bytes())?;
+        }
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("SubscriptionAck encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// The resting orders in a book, sent to a client right after it subscribes to the product so
+/// the updates that follow have something to apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub product: Product,
+    pub buys: u32,
+    pub sells: u32,
+}
+
+impl Encode for Snapshot {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        // SNAPSHOT:{product}:buys={buys},sells={sells}
+        let mut length = 0;
+        length += (&mut buffer[length..]).write(b"SNAPSHOT:")?;
+        length += (&mut buffer[length..]).write(self.product.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(b":buys=")?;
+        length += (&mut buffer[length..]).write(self.buys.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(b",sells=")?;
+        length += (&mut buffer[length..]).write(self.sells.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Snapshot encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+impl FromStr for Snapshot {
+    type Err = anyhow::Error;
+
+    /// Parses a `SNAPSHOT:` frame as it is encoded, without the newline.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid snapshot: {s}");
+        let rest = s.strip_prefix("SNAPSHOT:").ok_or_else(invalid)?;
+        let (product, counts) = rest.split_once(':').ok_or_else(invalid)?;
+        let (buys, sells) = counts
+            .strip_prefix("buys=")
+            .and_then(|counts| counts.split_once(",sells="))
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            product: product.parse()?,
+            buys: buys.parse().map_err(|_| invalid())?,
+            sells: sells.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Order {
     pub side: Side,
     pub product: Product,
+    /// Client supplied id (`@clid=<id>`), echoed in the ack and used to reject duplicates.
+    pub client_order_id: Option<String>,
+    /// `@venue=<name>`, `None` for the default venue.
+    pub venue: Option<VenueId>,
 }
 
 impl FromStr for Order {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut split = s.split(':');
+        Self::parse_with_case(s, ProductCase::Sensitive)
+    }
+}
+
+impl Order {
+    /// Parses `s` like `from_str`, matching the product name as `case` says.
+    pub fn parse_with_case(s: &str, case: ProductCase) -> anyhow::Result<Self> {
+        // {side}:{product}[@{key}={value}]*
+        let mut attributes = s.split('@');
+        let body = attributes.next().unwrap_or_default();
+
+        let mut split = body.split(':');
         let side = split.next().context("Message from client without side")?;
         let product = split
             .next()
             .context("Message from client without product")?;
+        // Orders have no quantity or price yet, so there is nothing else to read
+        if let Some(extra) = split.next() {
+            anyhow::bail!("Unexpected field after the product in order: {extra}");
+        }
 
         let side = side.parse()?;
-        let product = product.parse()?;
+        let product = Product::parse_with_case(product, case)?;
+
+        let mut client_order_id = None;
+        let mut venue = None;
+        for attribute in attributes {
+            let (key, value) = attribute
+                .split_once('=')
+                .with_context(|| format!("Malformed order attribute: {attribute}"))?;
+            match key {
+                "clid" => {
+                    anyhow::ensure!(
+                        !value.is_empty() && value.len() <= MAX_CLIENT_ORDER_ID_LEN,
+                        "Client order id must be 1 to {MAX_CLIENT_ORDER_ID_LEN} bytes",
+                    );
+                    client_order_id = Some(value.to_string());
+                }
+                "venue" => venue = Some(value.parse()?),
+                other => anyhow::bail!("Unknown order attribute: {other}"),
+            }
+        }
+
+        Ok(Self {
+            side,
+            product,
+            client_order_id,
+            venue,
+        })
+    }
+}
+
+/// The request line a client sends for this order, the inverse of `from_str`.
+impl Encode for Order {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // {side}:{product}[@clid={client_order_id}][@venue={venue}]
+        length += (&mut buffer[length..]).write(self.side.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(b":")?;
+        length += (&mut buffer[length..]).write(self.product.to_string().as_bytes())?;
+        if let Some(client_order_id) = &self.client_order_id {
+            length += (&mut buffer[length..]).write(b"@clid=")?;
+            length += (&mut buffer[length..]).write(client_order_id.as_bytes())?;
+        }
+        if let Some(venue) = &self.venue {
+            length += (&mut buffer[length..]).write(b"@venue=")?;
+            length += (&mut buffer[length..]).write(venue.as_str().as_bytes())?;
+        }
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Order encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Per-connection option a client can set with `OPTS:<option>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOption {
+    /// Re-enable Nagle's algorithm, trading latency for fewer packets.
+    Nagle,
+    /// Receive trades in `TRADES:` frames of up to this many trades instead of one frame each.
+    BatchTrades(NonZeroUsize),
+    /// Only hear back about orders that were rejected, accepted orders are not acked.
+    NackOnly,
+    /// Receive trades as `EXEC:` frames with every detail of the trade instead of `TRADE:`.
+    /// Takes precedence over `BatchTrades`.
+    RichTrades,
+    /// Compress everything after the `ACK:COMPRESS` reply as a raw deflate stream.
+    Compress,
+    /// Refuse `FOLLOW` requests for this client, and drop its current followers.
+    NoFollow,
+    /// End every line, both ways, with a CRC32 trailer, see `codec::CRC_TRAILER`. Lines from the
+    /// client without a matching one are rejected with `NACK:BAD_CRC`.
+    Crc,
+}
+
+impl FromStr for ClientOption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(size) = s.strip_prefix("batchtrades=") {
+            let size = size
+                .parse()
+                .with_context(|| format!("Invalid trade batch size: {size}"))?;
+            return Ok(Self::BatchTrades(size));
+        }
+
+        match s {
+            "nagle" => Ok(Self::Nagle),
+            "nackonly" => Ok(Self::NackOnly),
+            "richtrades" => Ok(Self::RichTrades),
+            "compress" => Ok(Self::Compress),
+            "nofollow" => Ok(Self::NoFollow),
+            "crc" => Ok(Self::Crc),
+            other => {
+                anyhow::bail!("Unknown option: {other}");
+            }
+        }
+    }
+}
+
+/// A single line received from a client.
+#[derive(Debug)]
+pub enum Request {
+    Order(Order),
+    Message(String),
+    Info,
+    Options(ClientOption),
+    /// `SUB:<product>[@conflate]`. With `@conflate` only the latest imbalance is sent when the
+    /// client is behind, rather than every update.
+    Subscribe(Subscription, bool),
+    Unsubscribe(Subscription),
+    /// The last `n` trades for a product, `TAPE:<product>:<n>`.
+    Tape(Product, usize),
+    /// `PING`, a liveness probe answered with `PONG`, even before the handshake.
+    Ping,
+    /// `QDEPTH`, how much is queued for the client, answered with `QDEPTH:<bytes>`.
+    QueueDepth,
+    /// `FOLLOW:<client_id>`, be told about the trades of another client's orders.
+    Follow(ClientId),
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_case(s, ProductCase::Sensitive)
+    }
+}
+
+impl Request {
+    /// Parses `s` like `from_str`, matching product names as `case` says.
+    pub fn parse_with_case(s: &str, case: ProductCase) -> anyhow::Result<Self> {
+        if s == "INFO" {
+            return Ok(Self::Info);
+        }
+        if s == "PING" {
+            return Ok(Self::Ping);
+        }
+        if s == "QDEPTH" {
+            return Ok(Self::QueueDepth);
+        }
+        if let Some(option) = s.strip_prefix("OPTS:") {
+            return Ok(Self::Options(option.parse()?));
+        }
+        if let Some(subscription) = s.strip_prefix("SUB:") {
+            let conflated = subscription.strip_suffix(CONFLATE_SUFFIX);
+            return Ok(Self::Subscribe(
+                Subscription::parse_with_case(conflated.unwrap_or(subscription), case)?,
+                conflated.is_some(),
+            ));
+        }
+        if let Some(subscription) = s.strip_prefix("UNSUB:") {
+            return Ok(Self::Unsubscribe(Subscription::parse_with_case(
+                subscription,
+                case,
+            )?));
+        }
+        if let Some(client_id) = s.strip_prefix("FOLLOW:") {
+            let client_id = client_id
+                .parse()
+                .with_context(|| format!("Invalid client id: {client_id}"))?;
+            return Ok(Self::Follow(ClientId(client_id)));
+        }
+        if let Some(tape) = s.strip_prefix("TAPE:") {
+            let (product, count) = tape
+                .split_once(':')
+                .context("Tape request without a trade count")?;
+            let count = count
+                .parse()
+                .with_context(|| format!("Invalid trade count: {count}"))?;
+            return Ok(Self::Tape(Product::parse_with_case(product, case)?, count));
+        }
 
-        Ok(Self { side, product })
+        // Lines that start with a side are orders, everything else is chat
+        let head = s.split(':').next().unwrap_or_default();
+        if head.parse::<Side>().is_ok() {
+            Ok(Self::Order(Order::parse_with_case(s, case)?))
+        } else {
+            Ok(Self::Message(s.to_string()))
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct OrderAck {
     pub product: Product,
+    pub client_order_id: Option<String>,
 }
 
 impl Encode for OrderAck {
     fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
-        // ACK:{product}
+        // ACK:{product}[@clid={client_order_id}]
 
         let mut length = 0;
         length += (&mut buffer[length..]).write(b"ACK:")?;
-        length += (&mut buffer[length..]).write(self.product.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(self.product.as_str().as_bytes())?;
+        if let Some(client_order_id) = &self.client_order_id {
+            length += (&mut buffer[length..]).write(b"@clid=")?;
+            length += (&mut buffer[length..]).write(client_order_id.as_bytes())?;
+        }
         length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
 
         tracing::debug!("OrderAck encoded: {:?}", &buffer[..length]);
 
         Ok(length)
     }
+
+    fn encoded_len(&self) -> usize {
+        let client_order_id_len = self
+            .client_order_id
+            .as_ref()
+            .map_or(0, |client_order_id| b"@clid=".len() + client_order_id.len());
+
+        b"ACK:".len() + self.product.as_str().len() + client_order_id_len + NEWLINE_ARRAY.len()
+    }
+}
+
+/// Answer to `INFO`, describing the running server.
+#[derive(Debug)]
+pub struct Info {
+    pub version: &'static str,
+    pub uptime: Duration,
+    pub clients: usize,
+}
+
+impl Encode for Info {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        // INFO:version={version},uptime={secs},clients={clients}
+        let mut length = 0;
+        length += (&mut buffer[length..]).write(b"INFO:version=")?;
+        length += (&mut buffer[length..]).write(self.version.as_bytes())?;
+        length += (&mut buffer[length..]).write(b",uptime=")?;
+        length += (&mut buffer[length..]).write(self.uptime.as_secs().to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(b",clients=")?;
+        length += (&mut buffer[length..]).write(self.clients.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Info encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// Why a request or connection was rejected. The codes are part of the protocol and stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// `UNKNOWN_PRODUCT`: the order's product is not in the product registry.
+    UnknownProduct,
+    /// `DUPLICATE`: the client order id was already used within the idempotency window.
+    Duplicate,
+    /// `HANDSHAKE_TIMEOUT`: no valid line arrived in time, the client is disconnected.
+    HandshakeTimeout,
+    /// `IDLE_TIMEOUT`: no valid line arrived for too long, the client is disconnected.
+    IdleTimeout,
+    /// `BOOK_FULL`: the order would rest, but the books hold as many orders as they may.
+    BookFull,
+    /// `RATE_LIMITED`: over the accept rate, the connection is closed.
+    RateLimited,
+    /// `OVERLOADED`: over the connection limit, the connection is closed.
+    Overloaded,
+    /// `NO_CREDIT`: the client has too many orders in flight, it may retry once one is acked.
+    NoCredit,
+    /// `DRAINING`: the server is draining for a restart, the connection is closed.
+    Draining,
+    /// `STARTING`: the order arrived during the startup grace period.
+    Starting,
+    /// `NOT_FOLLOWABLE`: the client to follow is not connected, or opted out of being followed.
+    NotFollowable,
+    /// `QUOTA`: the client was sent as many bytes as a session may get, the connection is closed.
+    Quota,
+    /// `BAD_CRC`: the line's CRC trailer is missing or does not match, it was dropped.
+    BadCrc,
+}
+
+impl RejectReason {
+    #[must_use]
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::UnknownProduct => "UNKNOWN_PRODUCT",
+            Self::Duplicate => "DUPLICATE",
+            Self::HandshakeTimeout => "HANDSHAKE_TIMEOUT",
+            Self::IdleTimeout => "IDLE_TIMEOUT",
+            Self::BookFull => "BOOK_FULL",
+            Self::RateLimited => "RATE_LIMITED",
+            Self::Overloaded => "OVERLOADED",
+            Self::NoCredit => "NO_CREDIT",
+            Self::Draining => "DRAINING",
+            Self::Starting => "STARTING",
+            Self::NotFollowable => "NOT_FOLLOWABLE",
+            Self::Quota => "QUOTA",
+            Self::BadCrc => "BAD_CRC",
+        }
+    }
+}
+
+impl Encode for RejectReason {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        Nack::from(*self).encode(buffer)
+    }
 }
 
+/// Rejection of a client request, sent only to that client.
 #[derive(Debug)]
+pub struct Nack {
+    pub reason: RejectReason,
+    /// Backoff hint for clients that were disconnected, sent in whole seconds.
+    pub retry_after: Option<Duration>,
+}
+
+impl From<RejectReason> for Nack {
+    fn from(reason: RejectReason) -> Self {
+        Self {
+            reason,
+            retry_after: None,
+        }
+    }
+}
+
+impl Encode for Nack {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        // NACK:{code}
+        let mut length = 0;
+        length += (&mut buffer[length..]).write(b"NACK:")?;
+        length += (&mut buffer[length..]).write(self.reason.code().as_bytes())?;
+        if let Some(retry_after) = self.retry_after {
+            // NACK:{code} retry_after={seconds}, rounded up so clients never retry early
+            let seconds = retry_after
+                .as_secs()
+                .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+            length += (&mut buffer[length..]).write(b" retry_after=")?;
+            length += (&mut buffer[length..]).write(seconds.to_string().as_bytes())?;
+        }
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Nack encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Trade {
     pub product: Product,
+    /// Where the trade happened, `None` for the default venue.
+    pub venue: Option<VenueId>,
+}
+
+impl Trade {
+    /// Writes `{product}[@venue={venue}]`, the part shared by `TRADE:` and `TRADES:`.
+    fn write_body(&self, mut buffer: &mut [u8]) -> std::io::Result<usize> {
+        let mut length = buffer.write(self.product.as_str().as_bytes())?;
+        if let Some(venue) = &self.venue {
+            length += buffer.write(b"@venue=")?;
+            length += buffer.write(venue.as_str().as_bytes())?;
+        }
+
+        Ok(length)
+    }
+
+    fn body_len(&self) -> usize {
+        self.product.as_str().len()
+            + self
+                .venue
+                .map_or(0, |venue| b"@venue=".len() + venue.as_str().len())
+    }
 }
 
 impl Encode for Trade {
     fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
         let mut length = 0;
-        // TRADE:{product}
+        // TRADE:{product}[@venue={venue}]
         length += (&mut buffer[length..]).write(b"TRADE:")?;
-        length += (&mut buffer[length..]).write(self.product.to_string().as_bytes())?;
+        length += self.write_body(&mut buffer[length..])?;
         length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
 
         tracing::debug!("Trade encoded: {:?}", &buffer[..length]);
 
         Ok(length)
     }
+
+    fn encoded_len(&self) -> usize {
+        b"TRADE:".len() + self.body_len() + NEWLINE_ARRAY.len()
+    }
+}
+
+/// A trade with all its details, for clients that enabled `ClientOption::RichTrades`.
+#[derive(Debug, Clone, Copy)]
+pub struct Execution {
+    pub product: Product,
+    pub trade_id: u64,
+    /// Side of the order that completed the trade.
+    pub side: Side,
+    /// Since the server started, sent in milliseconds.
+    pub timestamp: Duration,
+    /// Only sent for trades outside the default venue.
+    pub venue: Option<VenueId>,
+}
+
+impl Encode for Execution {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        // EXEC:product={product},id={trade_id},qty=1,side={side},ts={millis}[,venue={venue}]
+        // Orders are for a single unit each, so every trade is too. Books are not priced.
+        let mut length = 0;
+        length += (&mut buffer[length..]).write(b"EXEC:product=")?;
+        length += (&mut buffer[length..]).write(self.product.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(b",id=")?;
+        length += (&mut buffer[length..]).write(self.trade_id.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(b",qty=1,side=")?;
+        length += (&mut buffer[length..]).write(self.side.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(b",ts=")?;
+        length +=
+            (&mut buffer[length..]).write(self.timestamp.as_millis().to_string().as_bytes())?;
+        if let Some(venue) = &self.venue {
+            length += (&mut buffer[length..]).write(b",venue=")?;
+            length += (&mut buffer[length..]).write(venue.as_str().as_bytes())?;
+        }
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Execution encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// A batch of trades for a client that enabled `ClientOption::BatchTrades`, in the order they
+/// happened.
+#[derive(Debug)]
+pub struct Trades {
+    pub trades: Vec<Trade>,
+}
+
+impl Encode for Trades {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // TRADES:{product}[@venue={venue}],...
+        length += (&mut buffer[length..]).write(b"TRADES:")?;
+        for (index, trade) in self.trades.iter().enumerate() {
+            if index > 0 {
+                length += (&mut buffer[length..]).write(b",")?;
+            }
+            length += trade.write_body(&mut buffer[length..])?;
+        }
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Trades encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// An order sent straight back to its sender, see `OrderHandling::Echo`.
+#[derive(Debug)]
+pub struct Echo {
+    pub side: Side,
+    pub product: Product,
+}
+
+impl Encode for Echo {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // ECHO:{side}:{product}
+        length += (&mut buffer[length..]).write(b"ECHO:")?;
+        length += (&mut buffer[length..]).write(self.side.as_str().as_bytes())?;
+        length += (&mut buffer[length..]).write(b":")?;
+        length += (&mut buffer[length..]).write(self.product.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Echo encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// A trade as kept on the tape. Trade ids increase with every trade of a matcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapeEntry {
+    pub trade_id: u64,
+    /// Side of the order that completed the trade.
+    pub side: Side,
+}
+
+/// Response to a `TAPE` request: one line per trade, oldest first, then `END`.
+#[derive(Debug)]
+pub struct Tape {
+    pub product: Product,
+    pub trades: Vec<TapeEntry>,
+}
+
+impl Encode for Tape {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        let product = self.product.to_string();
+        for trade in &self.trades {
+            // TAPE:{product}:id={trade_id},side={side}
+            length += (&mut buffer[length..]).write(b"TAPE:")?;
+            length += (&mut buffer[length..]).write(product.as_bytes())?;
+            length += (&mut buffer[length..]).write(b":id=")?;
+            length += (&mut buffer[length..]).write(trade.trade_id.to_string().as_bytes())?;
+            length += (&mut buffer[length..]).write(b",side=")?;
+            length += (&mut buffer[length..]).write(trade.side.as_str().as_bytes())?;
+            length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+        }
+        length += (&mut buffer[length..]).write(b"END")?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Tape encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+}
+
+/// A frame encoded by the caller, written as is. Must include the trailing newline.
+#[derive(Debug, Clone)]
+pub struct FrameBytes(pub Vec<u8>);
+
+impl Encode for FrameBytes {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let length = (&mut buffer[0..]).write(&self.0)?;
+
+        tracing::debug!("FrameBytes encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Order book imbalance signal, see `Book::imbalance`.
+#[derive(Debug)]
+pub struct Imbalance {
+    pub product: Product,
+    pub value: f64,
+}
+
+impl Encode for Imbalance {
+    fn encode(&self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut length = 0;
+        // IMBAL:{product}:{value}
+        length += (&mut buffer[length..]).write(b"IMBAL:")?;
+        length += (&mut buffer[length..]).write(self.product.to_string().as_bytes())?;
+        length += (&mut buffer[length..]).write(b":")?;
+        length += (&mut buffer[length..]).write(format!("{:.2}", self.value).as_bytes())?;
+        length += (&mut buffer[length..]).write(&NEWLINE_ARRAY)?;
+
+        tracing::debug!("Imbalance encoded: {:?}", &buffer[..length]);
+
+        Ok(length)
+    }
 }
 
 #[cfg(test)]
@@ -223,4 +1177,365 @@ mod tests {
 
         assert_eq!(&buffer[..length], b"ACK:MESSAGE\n");
     }
+
+    #[test]
+    fn test_order_with_client_order_id() {
+        let order: Order = "BUY:APPLE@clid=abc123".parse().unwrap();
+
+        assert_eq!(order.side, Side::Buy);
+        assert_eq!(order.product, Product::Apples);
+        assert_eq!(order.client_order_id.as_deref(), Some("abc123"));
+
+        assert!("BUY:APPLE@clid=".parse::<Order>().is_err());
+        assert!("BUY:APPLE@desk=A".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn test_order_with_venue() {
+        let order: Order = "SELL:APPLE@clid=1@venue=NYSE".parse().unwrap();
+        assert_eq!(
+            order.venue.map(|venue| venue.to_string()).as_deref(),
+            Some("NYSE")
+        );
+        assert_eq!(order.client_order_id.as_deref(), Some("1"));
+
+        let mut buffer = [0; 1024];
+        let length = order.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"SELL:APPLE@clid=1@venue=NYSE\n");
+
+        assert_eq!("BUY:APPLE".parse::<Order>().unwrap().venue, None);
+        assert!("BUY:APPLE@venue=".parse::<Order>().is_err());
+        assert!("BUY:APPLE@venue=nyse".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn test_order_rejects_extra_fields() {
+        let order: Order = "BUY:APPLE".parse().unwrap();
+        assert_eq!(order.side, Side::Buy);
+        assert_eq!(order.product, Product::Apples);
+
+        // No quantities yet
+        assert!("BUY:APPLE:5".parse::<Order>().is_err());
+        assert!("BUY:APPLE:junk:more".parse::<Order>().is_err());
+        assert!("BUY:APPLE:".parse::<Order>().is_err());
+        assert!("BUY:APPLE:5@clid=1".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn test_order_ack_encode_with_client_order_id() {
+        let order_ack = OrderAck {
+            product: Product::Apples,
+            client_order_id: Some("abc123".to_string()),
+        };
+
+        let mut buffer = [0; 1024];
+        let length = order_ack.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"ACK:APPLE@clid=abc123\n");
+    }
+
+    #[test]
+    fn test_product_names() {
+        assert_eq!("APPLE".parse::<Product>().unwrap(), Product::Apples);
+
+        let banana: Product = "BANANA".parse().unwrap();
+        assert_eq!(banana.to_string(), "BANANA");
+        assert!(matches!(banana, Product::Other(_)));
+
+        assert!("".parse::<Product>().is_err());
+        assert!("banana".parse::<Product>().is_err());
+        assert!("A_VERY_LONG_PRODUCT".parse::<Product>().is_err());
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let frames: [Box<dyn Encode>; 8] = [
+            Box::new(Login {
+                client_id: ClientId(0),
+            }),
+            Box::new(Login {
+                client_id: ClientId(65_535),
+            }),
+            Box::new(Message {
+                origin_client_id: ClientId(10),
+                message: "hello".to_string(),
+            }),
+            Box::new(OrderAck {
+                product: Product::Tomatoes,
+                client_order_id: None,
+            }),
+            Box::new(OrderAck {
+                product: "BANANA".parse().unwrap(),
+                client_order_id: Some("abc123".to_string()),
+            }),
+            Box::new(Trade {
+                product: Product::Pears,
+                venue: None,
+            }),
+            Box::new(Trade {
+                product: Product::Pears,
+                venue: Some("NYSE".parse().unwrap()),
+            }),
+            // Uses the default
+            Box::new(Info {
+                version: "1.0.0",
+                uptime: Duration::from_secs(90),
+                clients: 3,
+            }),
+        ];
+        for frame in frames {
+            let mut buffer = [0; 1024];
+            let length = frame.encode(&mut buffer).unwrap();
+            assert_eq!(frame.encoded_len(), length, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn test_client_id_display() {
+        assert_eq!(ClientId(7).to_string(), "7");
+        assert_eq!(ClientId::from(7), ClientId(7));
+
+        let mut buffer = [0; 1024];
+        let length = Login {
+            client_id: ClientId(7),
+        }
+        .encode(&mut buffer)
+        .unwrap();
+        assert_eq!(&buffer[..length], b"LOGIN:7\n");
+
+        let message = Message {
+            origin_client_id: ClientId(65_535),
+            message: "hi".to_string(),
+        };
+        let length = message.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"MESSAGE:65535 hi\n");
+    }
+
+    #[test]
+    fn test_info_encode() {
+        let info = Info {
+            version: "1.2.3",
+            uptime: Duration::from_millis(61_500),
+            clients: 2,
+        };
+
+        let mut buffer = [0; 1024];
+        let length = info.encode(&mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..length],
+            b"INFO:version=1.2.3,uptime=61,clients=2\n"
+        );
+    }
+
+    #[test]
+    fn test_imbalance_encode() {
+        let imbalance = Imbalance {
+            product: Product::Apples,
+            value: -1.0 / 3.0,
+        };
+
+        let mut buffer = [0; 1024];
+        let length = imbalance.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"IMBAL:APPLE:-0.33\n");
+    }
+
+    #[test]
+    fn test_batch_trades_option() {
+        assert_eq!(
+            "batchtrades=3".parse::<ClientOption>().unwrap(),
+            ClientOption::BatchTrades(NonZeroUsize::new(3).unwrap())
+        );
+        assert!("batchtrades=0".parse::<ClientOption>().is_err());
+        assert!("batchtrades=".parse::<ClientOption>().is_err());
+    }
+
+    #[test]
+    fn test_nack_only_option() {
+        assert_eq!(
+            "nackonly".parse::<ClientOption>().unwrap(),
+            ClientOption::NackOnly
+        );
+        assert!("NACKONLY".parse::<ClientOption>().is_err());
+    }
+
+    #[test]
+    fn test_encode_execution() {
+        assert_eq!(
+            "richtrades".parse::<ClientOption>().unwrap(),
+            ClientOption::RichTrades
+        );
+
+        let execution = Execution {
+            product: Product::Apples,
+            trade_id: 7,
+            side: Side::Sell,
+            timestamp: Duration::from_millis(1500),
+            venue: None,
+        };
+        let mut buffer = [0; 1024];
+        let length = execution.encode(&mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..length],
+            b"EXEC:product=APPLE,id=7,qty=1,side=SELL,ts=1500\n"
+        );
+
+        let execution = Execution {
+            venue: Some("A".parse().unwrap()),
+            ..execution
+        };
+        let length = execution.encode(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..length],
+            b"EXEC:product=APPLE,id=7,qty=1,side=SELL,ts=1500,venue=A\n"
+        );
+    }
+
+    #[test]
+    fn test_subscription_requests() {
+        assert!(matches!(
+            "SUB:APPLE".parse::<Request>().unwrap(),
+            Request::Subscribe(Subscription::Product(Product::Apples), false)
+        ));
+        assert!(matches!(
+            "SUB:APPLE@conflate".parse::<Request>().unwrap(),
+            Request::Subscribe(Subscription::Product(Product::Apples), true)
+        ));
+        assert!(matches!(
+            "UNSUB:PEAR".parse::<Request>().unwrap(),
+            Request::Unsubscribe(Subscription::Product(Product::Pears))
+        ));
+        assert!(matches!(
+            "SUB:*".parse::<Request>().unwrap(),
+            Request::Subscribe(Subscription::AllProducts, false)
+        ));
+        assert!("SUB:apple".parse::<Request>().is_err());
+
+        let ack = SubscriptionAck {
+            subscription: Subscription::Product(Product::Apples),
+            subscribed: false,
+            conflate: false,
+        };
+        let mut buffer = [0; 1024];
+        let length = ack.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"ACK:UNSUB:APPLE\n");
+
+        let ack = SubscriptionAck {
+            subscription: Subscription::AllProducts,
+            subscribed: true,
+            conflate: true,
+        };
+        let length = ack.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"ACK:SUB:*@conflate\n");
+
+        let snapshot = Snapshot {
+            product: Product::Apples,
+            buys: 3,
+            sells: 0,
+        };
+        let length = snapshot.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"SNAPSHOT:APPLE:buys=3,sells=0\n");
+        assert_eq!(
+            "SNAPSHOT:APPLE:buys=3,sells=0".parse::<Snapshot>().unwrap(),
+            snapshot
+        );
+        assert!("SNAPSHOT:APPLE:buys=3".parse::<Snapshot>().is_err());
+    }
+
+    #[test]
+    fn test_follow_request_and_encode() {
+        assert!(matches!(
+            "FOLLOW:42".parse::<Request>().unwrap(),
+            Request::Follow(ClientId(42))
+        ));
+        assert!("FOLLOW:".parse::<Request>().is_err());
+        assert!("FOLLOW:-1".parse::<Request>().is_err());
+        assert_eq!(
+            "nofollow".parse::<ClientOption>().unwrap(),
+            ClientOption::NoFollow
+        );
+
+        let mut buffer = [0; 1024];
+        let ack = FollowAck {
+            client_id: ClientId(42),
+        };
+        let length = ack.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"ACK:FOLLOW:42\n");
+
+        let trade = FollowedTrade {
+            client_id: ClientId(42),
+            product: Product::Pears,
+        };
+        let length = trade.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"FOLLOWED_TRADE:42:PEAR\n");
+    }
+
+    #[test]
+    fn test_tape_request_and_encode() {
+        assert!(matches!(
+            "TAPE:APPLE:2".parse::<Request>().unwrap(),
+            Request::Tape(Product::Apples, 2)
+        ));
+        assert!("TAPE:APPLE".parse::<Request>().is_err());
+        assert!("TAPE:APPLE:-1".parse::<Request>().is_err());
+
+        let tape = Tape {
+            product: Product::Apples,
+            trades: vec![
+                TapeEntry {
+                    trade_id: 1,
+                    side: Side::Sell,
+                },
+                TapeEntry {
+                    trade_id: 2,
+                    side: Side::Buy,
+                },
+            ],
+        };
+        let mut buffer = [0; 1024];
+        let length = tape.encode(&mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..length],
+            b"TAPE:APPLE:id=1,side=SELL\nTAPE:APPLE:id=2,side=BUY\nEND\n"
+        );
+    }
+
+    #[test]
+    fn test_nack_encode_with_retry_after() {
+        let nack = Nack {
+            reason: RejectReason::RateLimited,
+            retry_after: Some(Duration::from_millis(4_200)),
+        };
+
+        let mut buffer = [0; 1024];
+        let length = nack.encode(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"NACK:RATE_LIMITED retry_after=5\n");
+    }
+
+    #[test]
+    fn test_reject_reason_codes() {
+        for (reason, expected) in [
+            (RejectReason::UnknownProduct, "NACK:UNKNOWN_PRODUCT\n"),
+            (RejectReason::Duplicate, "NACK:DUPLICATE\n"),
+            (RejectReason::HandshakeTimeout, "NACK:HANDSHAKE_TIMEOUT\n"),
+            (RejectReason::IdleTimeout, "NACK:IDLE_TIMEOUT\n"),
+            (RejectReason::BookFull, "NACK:BOOK_FULL\n"),
+            (RejectReason::RateLimited, "NACK:RATE_LIMITED\n"),
+            (RejectReason::Overloaded, "NACK:OVERLOADED\n"),
+            (RejectReason::NoCredit, "NACK:NO_CREDIT\n"),
+            (RejectReason::Draining, "NACK:DRAINING\n"),
+        ] {
+            let mut buffer = [0; 1024];
+            let length = reason.encode(&mut buffer).unwrap();
+
+            assert_eq!(&buffer[..length], expected.as_bytes(), "{reason:?}");
+        }
+    }
 }
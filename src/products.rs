@@ -0,0 +1,50 @@
+use std::collections::HashSet;
+
+use crate::models::Product;
+
+/// What the server does with an order for a product that is not registered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownProductPolicy {
+    /// Answer the client with `NACK:UNKNOWN_PRODUCT`.
+    RejectWithNack,
+    /// Log and drop the order.
+    #[default]
+    SilentlyDrop,
+    /// Register the product on the fly and process the order normally.
+    AutoRegister,
+}
+
+/// The set of products that can be traded.
+#[derive(Debug, Clone)]
+pub struct ProductRegistry {
+    products: HashSet<Product>,
+}
+
+impl Default for ProductRegistry {
+    fn default() -> Self {
+        Self::new(Product::BUILTIN)
+    }
+}
+
+impl ProductRegistry {
+    pub fn new(products: impl IntoIterator<Item = Product>) -> Self {
+        Self {
+            products: products.into_iter().collect(),
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, product: Product) -> bool {
+        self.products.contains(&product)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty()
+    }
+
+    /// Returns `false` if the product was already registered.
+    pub fn register(&mut self, product: Product) -> bool {
+        self.products.insert(product)
+    }
+}
@@ -0,0 +1,101 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest PROXY protocol v1 header, including the trailing `\r\n`.
+const MAX_HEADER_LEN: usize = 107;
+
+/// Reads a PROXY protocol v1 header off the front of `stream`.
+///
+/// The header looks like `PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\r\n`. Returns the address
+/// of the client the proxy connected for, `None` for `PROXY UNKNOWN` where the proxy does not
+/// know it. Reads a byte at a time, so nothing past the header is consumed.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut header = Vec::with_capacity(MAX_HEADER_LEN);
+    while !header.ends_with(b"\r\n") {
+        anyhow::ensure!(header.len() < MAX_HEADER_LEN, "PROXY header too long");
+        header.push(stream.read_u8().await?);
+    }
+
+    parse_header(std::str::from_utf8(&header)?)
+}
+
+/// Parses a whole PROXY protocol v1 header, see `read_header`.
+pub fn parse_header(header: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let line = header
+        .strip_suffix("\r\n")
+        .ok_or_else(|| anyhow::anyhow!("PROXY header without CRLF: {header:?}"))?;
+    let mut fields = line.split(' ');
+    anyhow::ensure!(
+        fields.next() == Some("PROXY"),
+        "Not a PROXY header: {line:?}"
+    );
+
+    let protocol = fields.next().unwrap_or_default();
+    if protocol == "UNKNOWN" {
+        // Anything may follow, and is to be ignored
+        return Ok(None);
+    }
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        anyhow::bail!("Malformed PROXY header: {line:?}");
+    };
+    let source: IpAddr = source.parse()?;
+    anyhow::ensure!(
+        matches!(
+            (protocol, source),
+            ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_))
+        ),
+        "Address does not match the protocol in PROXY header: {line:?}"
+    );
+
+    Ok(Some(SocketAddr::new(source, source_port.parse()?)))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\r\n").unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_header("PROXY TCP6 2001:db8::1 2001:db8::2 443 8888\r\n").unwrap(),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+        assert_eq!(parse_header("PROXY UNKNOWN\r\n").unwrap(), None);
+
+        for invalid in [
+            "PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n",
+            "PROXY TCP6 203.0.113.7 10.0.0.1 51234 8888\r\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 70000 8888\r\n",
+            "BUY:APPLE\r\n",
+        ] {
+            assert!(parse_header(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header_leaves_the_rest() {
+        let mut stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\r\nBUY:APPLE\n"[..];
+
+        let peer = read_header(&mut stream).await.unwrap();
+        assert_eq!(peer, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(stream, b"BUY:APPLE\n");
+
+        let mut endless = &[b'A'; 200][..];
+        assert!(read_header(&mut endless).await.is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Token bucket holding up to `capacity` tokens, refilled at `refill_per_sec`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket allowing `rate` acquisitions per second, with bursts up to `rate`.
+    #[must_use]
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self::with_burst(rate, rate, now)
+    }
+
+    #[must_use]
+    pub fn with_burst(rate: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            capacity: f64::from(burst),
+            tokens: f64::from(burst),
+            refill_per_sec: f64::from(rate),
+            last_refill: now,
+        }
+    }
+
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if self.has_token(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether `try_acquire` would succeed at `now`, without taking the token.
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(self.refill_per_sec, self.tokens)
+            .min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens >= 1.0
+    }
+
+    /// How long until the next acquisition succeeds, as of the last `try_acquire` or
+    /// `has_token`.
+    #[must_use]
+    pub fn time_until_available(&self) -> Duration {
+        let missing = (1.0 - self.tokens).max(0.0);
+        Duration::try_from_secs_f64(missing / self.refill_per_sec).unwrap_or(Duration::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+        assert!(!bucket.try_acquire(start));
+
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(500)));
+        assert_eq!(bucket.time_until_available(), Duration::from_millis(500));
+    }
+}
@@ -1,74 +1,1085 @@
-use std::{fmt::Debug, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
+use bytes::{Buf, BytesMut};
+use futures::future::BoxFuture;
 use tokio::{
-    net::ToSocketAddrs,
-    sync::mpsc::{Receiver, Sender},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+        oneshot, Semaphore,
+    },
+    task::JoinSet,
+    time::Instant,
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    clock::{Clock, TokioClock},
+    codec::{Codec, TextCodec},
+    config::Config,
+    credit::OrderCredits,
     decoder::{DecoderEvent, DecoderTaskControl},
-    encoder::EncoderTaskControl,
+    encoder::{ClientFilter, EncoderTaskControl},
+    idempotency::RecentOrderIds,
     matcher::Matcher,
-    models::{ClientId, Message, OrderAck, Side, Trade},
+    models::{
+        ClientId, ClientOption, Echo, Encode, Execution, FollowAck, FollowedTrade, Imbalance, Info,
+        Nack, Order, OrderAck, Product, RejectReason, Request, Snapshot, Subscription,
+        SubscriptionAck, Tape,
+    },
+    products::{ProductRegistry, UnknownProductPolicy},
+    proxy,
+    rate_limit::TokenBucket,
+    sink::{NoopTradeSink, TradeSink},
 };
 
+const DEFAULT_CLIENT_ORDER_ID_WINDOW: Duration = Duration::from_mins(1);
+const DEFAULT_OVERLOAD_RETRY_AFTER: Duration = Duration::from_secs(5);
+const DEFAULT_REJECTED_INTENT_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long a proxied connection has to send its PROXY header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most of a rejected connection's first line that is logged.
+const REJECTED_INTENT_LEN: usize = 256;
+/// Starts the book checkpoints in sources replayed by `Server::run_with_source`.
+const SNAPSHOT_PREFIX: &[u8] = b"SNAPSHOT:";
+/// Orders replayed by `Server::run_with_source` are placed under this id. Source ports and
+/// sequential ids are never 0, so it is never given to a client.
+pub const REPLAY_CLIENT_ID: ClientId = ClientId(0);
+const DEFAULT_MAX_CONCURRENT_SETUPS: NonZeroUsize = NonZeroUsize::new(64).unwrap();
+/// How often a paused accept loop checks whether the encoder has drained.
+const BACKPRESSURE_RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the server does with the orders it receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderHandling {
+    /// Ack and match them.
+    #[default]
+    Match,
+    /// Send every order straight back to its sender as `ECHO:<side>:<product>`, without acks,
+    /// trades or market data. For clients developing against the protocol.
+    Echo,
+}
+
+/// What happens to orders that arrive during `ServerConfig::startup_grace_period`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartupOrderHandling {
+    /// Reject them with `NACK:STARTING`.
+    #[default]
+    Reject,
+    /// Hold them, and process them in arrival order once the grace period is over.
+    Queue,
+}
+
+/// How connected clients are numbered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientIdAllocation {
+    /// Use the client's source port.
+    #[default]
+    SourcePort,
+    /// Count up from 1, skipping ids still in use. Predictable, e.g. for asserting exact frames
+    /// in tests.
+    Sequential,
+}
+
+/// What the server does when a frame cannot be handed to the encoder, i.e. its channel is
+/// closed. Either way the server shuts down once it notices the encoder is gone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SendErrorPolicy {
+    /// Log the error and keep serving. The state changes behind the lost frames are kept.
+    #[default]
+    LogAndContinue,
+    /// Stop the server with the error.
+    Stop,
+}
+
+/// Operator commands, sent through the channel given to `Server::with_admin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Refuse new connections from now on, and disconnect the remaining clients with `BYE` once
+    /// the duration has passed. The server keeps running until it is cancelled.
+    Drain(Duration),
+    /// Stop reading from clients, leaving them connected. Nothing they send is handled until
+    /// `ResumeDecoding`, e.g. to stop matching during maintenance.
+    PauseDecoding,
+    /// Continue reading from clients where `PauseDecoding` left off.
+    ResumeDecoding,
+}
+
+/// Something that happened on the server, published to the receivers of `Server::with_events`,
+/// e.g. for dashboards.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    ClientConnected(ClientId),
+    ClientDisconnected(ClientId),
+    Trade(Execution),
+}
+
+/// Why a server could not be built from its `ServerConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerError {
+    /// No product is registered, so every order would be rejected. Registering products on the
+    /// fly with `UnknownProductPolicy::AutoRegister` is the way to start without any.
+    NoProducts,
+    /// The address to listen on is taken, e.g. by another server. Binding to another port may
+    /// work.
+    AddressInUse,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoProducts => write!(f, "No products are registered"),
+            Self::AddressInUse => write!(f, "Address is already in use"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Global cap on accepted connections per second. Connections over the cap are closed
+    /// right after `accept()`, before they are handed to the encoder/decoder.
+    pub max_accept_rate: Option<u32>,
+    /// Cap on concurrently connected clients. Connections over the cap are closed right after
+    /// `accept()`.
+    pub max_connections: Option<usize>,
+    /// Backoff hint sent to connections closed because of `max_connections`.
+    pub overload_retry_after: Duration,
+    /// How long connections closed because of `max_connections` get to send their first line,
+    /// which is logged to show what the client wanted. `None` closes them right away.
+    pub rejected_intent_timeout: Option<Duration>,
+    /// Cap on orders per client whose ack has not been written yet. Orders over the cap are
+    /// rejected with `NACK:NO_CREDIT`.
+    pub max_in_flight_orders: Option<usize>,
+    /// How long a client order id is remembered for duplicate detection.
+    pub client_order_id_window: Duration,
+    pub products: ProductRegistry,
+    pub unknown_product_policy: UnknownProductPolicy,
+    pub order_handling: OrderHandling,
+    /// How long after `bind` orders are not matched yet, while the rest of the system gets
+    /// ready. Clients can connect and subscribe in the meantime.
+    pub startup_grace_period: Option<Duration>,
+    pub startup_order_handling: StartupOrderHandling,
+    pub client_id_allocation: ClientIdAllocation,
+    pub send_error_policy: SendErrorPolicy,
+    /// Expect every connection to start with a PROXY protocol v1 header, as sent by load
+    /// balancers, and treat the client address in it as the peer's. Connections without one
+    /// are closed.
+    pub proxy_protocol: bool,
+    /// `SO_SNDBUF` for accepted connections, for clients receiving a lot of market data. `None`
+    /// keeps the OS default. The OS may round the size, Linux doubles it.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` for accepted connections, see `send_buffer_size`.
+    pub recv_buffer_size: Option<usize>,
+    /// Cap on accepted connections still being set up, e.g. waiting for their PROXY header.
+    /// Setup runs in its own task, so a slow client does not hold up the ones behind it. Further
+    /// connections wait to be accepted until a setup finishes.
+    pub max_concurrent_setups: NonZeroUsize,
+    /// Stop accepting connections while fewer than this many slots are free in the encoder
+    /// channel, resuming once it drains.
+    pub min_encoder_capacity: Option<usize>,
+    /// Book imbalance levels. An `IMBAL` frame is published whenever a product's imbalance
+    /// moves across one of them. Empty disables the feed.
+    pub imbalance_thresholds: Vec<f64>,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_accept_rate: None,
+            max_connections: None,
+            overload_retry_after: DEFAULT_OVERLOAD_RETRY_AFTER,
+            rejected_intent_timeout: Some(DEFAULT_REJECTED_INTENT_TIMEOUT),
+            max_in_flight_orders: None,
+            client_order_id_window: DEFAULT_CLIENT_ORDER_ID_WINDOW,
+            products: ProductRegistry::default(),
+            unknown_product_policy: UnknownProductPolicy::default(),
+            order_handling: OrderHandling::default(),
+            startup_grace_period: None,
+            startup_order_handling: StartupOrderHandling::default(),
+            client_id_allocation: ClientIdAllocation::default(),
+            send_error_policy: SendErrorPolicy::default(),
+            proxy_protocol: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            max_concurrent_setups: DEFAULT_MAX_CONCURRENT_SETUPS,
+            min_encoder_capacity: None,
+            imbalance_thresholds: Vec::new(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Server {
     listener: tokio::net::TcpListener,
 
     // Cell
     matcher: Matcher,
+    accept_limiter: Option<TokenBucket>,
+    max_connections: Option<usize>,
+    overload_retry_after: Duration,
+    rejected_intent_timeout: Option<Duration>,
+    order_credits: Option<OrderCredits>,
+    recent_order_ids: RecentOrderIds,
+    products: ProductRegistry,
+    unknown_product_policy: UnknownProductPolicy,
+    order_handling: OrderHandling,
+    startup_order_handling: StartupOrderHandling,
+    // When the startup grace period ends, `None` once it has
+    ready_at: Option<Instant>,
+    // Orders held for `StartupOrderHandling::Queue` with when they were decoded, oldest first
+    startup_queue: Vec<(ClientId, Order, Instant)>,
+    client_id_allocation: ClientIdAllocation,
+    // Next candidate for `ClientIdAllocation::Sequential`
+    next_client_id: u16,
+    send_error_policy: SendErrorPolicy,
+    proxy_protocol: bool,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    setup_permits: Arc<Semaphore>,
+    // Connected clients with their peer address, the one from the PROXY header if there was one
+    clients: HashMap<ClientId, SocketAddr>,
+    started_at: Instant,
+    min_encoder_capacity: Option<usize>,
+    imbalance_thresholds: Vec<f64>,
+    // Number of thresholds at or below the last published imbalance, per product
+    imbalance_levels: HashMap<Product, usize>,
+    // Recipients of market data per product. Shared with in-flight broadcasts, copied on write
+    subscriptions: HashMap<Product, Arc<HashSet<ClientId>>>,
+    // Recipients of market data for every product, `SUB:*`
+    all_products_subscribers: Arc<HashSet<ClientId>>,
+    // Followers per followed client. Shared with in-flight broadcasts, copied on write
+    followers: HashMap<ClientId, Arc<HashSet<ClientId>>>,
+    // Clients that opted out of being followed with `ClientOption::NoFollow`
+    unfollowable: HashSet<ClientId>,
+    admin_receiver: Option<Receiver<AdminCommand>>,
+    events: Option<broadcast::Sender<ServerEvent>>,
+    trade_sink: Arc<dyn TradeSink>,
+    draining: bool,
+    // When the clients left after a drain are disconnected
+    drain_deadline: Option<Instant>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Server {
     pub async fn bind<T: ToSocketAddrs + Debug + Send>(addr: T) -> anyhow::Result<Self> {
-        tracing::info!("Starting server on {addr:?}");
+        Self::bind_with_config(addr, ServerConfig::default()).await
+    }
+
+    /// Fails with `ServerError::NoProducts` if `config` registers no product and does not
+    /// register them on the fly either, and with `ServerError::AddressInUse` if `addr` is taken.
+    pub async fn bind_with_config<T: ToSocketAddrs + Debug + Send>(
+        addr: T,
+        config: ServerConfig,
+    ) -> anyhow::Result<Self> {
+        tracing::info!("Starting server on {addr:?} with {config:?}");
+        if config.products.is_empty()
+            && config.unknown_product_policy != UnknownProductPolicy::AutoRegister
+        {
+            return Err(ServerError::NoProducts.into());
+        }
+        let address = format!("{addr:?}");
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                return Err(anyhow::Error::new(ServerError::AddressInUse)
+                    .context(format!("Failed to bind to {address}")));
+            }
+            result => result.with_context(|| format!("Failed to bind to {address}"))?,
+        };
+        let now = config.clock.now();
         Ok(Self {
-            listener: tokio::net::TcpListener::bind(addr).await?,
+            listener,
             matcher: Matcher::new(),
+            accept_limiter: config
+                .max_accept_rate
+                .map(|rate| TokenBucket::new(rate, now)),
+            max_connections: config.max_connections,
+            overload_retry_after: config.overload_retry_after,
+            rejected_intent_timeout: config.rejected_intent_timeout,
+            order_credits: config.max_in_flight_orders.map(OrderCredits::new),
+            recent_order_ids: RecentOrderIds::new(config.client_order_id_window),
+            products: config.products,
+            unknown_product_policy: config.unknown_product_policy,
+            order_handling: config.order_handling,
+            startup_order_handling: config.startup_order_handling,
+            ready_at: config.startup_grace_period.map(|period| now + period),
+            startup_queue: Vec::new(),
+            client_id_allocation: config.client_id_allocation,
+            next_client_id: 1,
+            send_error_policy: config.send_error_policy,
+            proxy_protocol: config.proxy_protocol,
+            send_buffer_size: config.send_buffer_size,
+            recv_buffer_size: config.recv_buffer_size,
+            setup_permits: Arc::new(Semaphore::new(config.max_concurrent_setups.get())),
+            clients: HashMap::new(),
+            started_at: now,
+            min_encoder_capacity: config.min_encoder_capacity,
+            imbalance_thresholds: config.imbalance_thresholds,
+            imbalance_levels: HashMap::new(),
+            subscriptions: HashMap::new(),
+            all_products_subscribers: Arc::default(),
+            followers: HashMap::new(),
+            unfollowable: HashSet::new(),
+            admin_receiver: None,
+            events: None,
+            trade_sink: Arc::new(NoopTradeSink),
+            draining: false,
+            drain_deadline: None,
+            clock: config.clock,
         })
     }
 
-    async fn handle_new_client(
+    /// Binds to `config.bind` with the server settings from `config`. The decoder and encoder
+    /// take theirs from `Config::decoder_config` and `Config::encoder_config`.
+    pub async fn from_config(config: &Config) -> anyhow::Result<Self> {
+        Self::bind_with_config(config.bind.as_str(), config.server_config()?).await
+    }
+
+    /// The address the server listens on, e.g. to find the port picked when binding to port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The address `client_id` connected from, the client's own one for connections through a
+    /// PROXY protocol proxy. `None` for clients that are not connected.
+    #[must_use]
+    pub fn peer_addr(&self, client_id: ClientId) -> Option<SocketAddr> {
+        self.clients.get(&client_id).copied()
+    }
+
+    /// Replaces the matcher, e.g. to configure per-product matching engines.
+    #[must_use]
+    pub fn with_matcher(mut self, matcher: Matcher) -> Self {
+        self.matcher = matcher;
+        self
+    }
+
+    /// Takes `AdminCommand`s from `receiver` while running.
+    #[must_use]
+    pub fn with_admin(mut self, receiver: Receiver<AdminCommand>) -> Self {
+        self.admin_receiver = Some(receiver);
+        self
+    }
+
+    /// Publishes `ServerEvent`s on `sender` while running. Receivers that fall behind miss
+    /// events, the server never waits for them.
+    #[must_use]
+    pub fn with_events(mut self, sender: broadcast::Sender<ServerEvent>) -> Self {
+        self.events = Some(sender);
+        self
+    }
+
+    /// Hands every executed trade to `sink`, see `TradeSink`.
+    #[must_use]
+    pub fn with_trade_sink(mut self, sink: Arc<dyn TradeSink>) -> Self {
+        self.trade_sink = sink;
+        self
+    }
+
+    fn publish(&self, event: ServerEvent) {
+        if let Some(events) = &self.events {
+            // Fails only if nobody is subscribed at the moment
+            let _ = events.send(event);
+        }
+    }
+
+    /// Returns why a new connection has to be closed, if the server is draining or it is over
+    /// the accept rate or the connection limit.
+    fn reject_connection(&mut self) -> Option<Nack> {
+        if self.draining {
+            return Some(RejectReason::Draining.into());
+        }
+
+        let now = self.clock.now();
+        if let Some(limiter) = &mut self.accept_limiter {
+            if !limiter.try_acquire(now) {
+                return Some(Nack {
+                    reason: RejectReason::RateLimited,
+                    retry_after: Some(limiter.time_until_available()),
+                });
+            }
+        }
+
+        if self
+            .max_connections
+            .is_some_and(|max| self.clients.len() >= max)
+        {
+            return Some(Nack {
+                reason: RejectReason::Overloaded,
+                retry_after: Some(self.overload_retry_after),
+            });
+        }
+
+        None
+    }
+
+    /// Best effort, the connection is closed right after either way. The send buffer of a new
+    /// connection is empty, so this does not block the accept loop.
+    async fn send_rejection(stream: &mut TcpStream, nack: &Nack) {
+        let mut buffer = [0; 128];
+        let result = match nack.encode(&mut buffer) {
+            Ok(length) => stream
+                .write_all(&buffer[..length])
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::debug!("Failed to send {nack:?}: {e:?}");
+        }
+    }
+
+    /// Sends `nack` and closes the connection. Connections over `max_connections` first get
+    /// `rejected_intent_timeout` to send their first line, on a task of their own so the accept
+    /// loop is not held up.
+    async fn close_rejected(&self, mut stream: TcpStream, socket: SocketAddr, nack: Nack) {
+        tracing::warn!("Closing connection from {socket:?}: {nack:?}");
+        let (RejectReason::Overloaded, Some(timeout)) = (nack.reason, self.rejected_intent_timeout)
+        else {
+            Self::send_rejection(&mut stream, &nack).await;
+            return;
+        };
+
+        let timed_out = self.clock.sleep(timeout);
+        tokio::spawn(async move {
+            let mut buffer = [0; REJECTED_INTENT_LEN];
+            let read = tokio::select! {
+                read = stream.read(&mut buffer) => Some(read),
+                () = timed_out => None,
+            };
+            match read {
+                Some(Ok(length)) if length > 0 => {
+                    let intent = String::from_utf8_lossy(&buffer[..length]);
+                    let intent = intent.lines().next().unwrap_or_default();
+                    tracing::info!("Rejected connection from {socket:?} wanted: {intent:?}");
+                }
+                _ => tracing::info!("Rejected connection from {socket:?} sent nothing"),
+            }
+            Self::send_rejection(&mut stream, &nack).await;
+        });
+    }
+
+    /// The address of the client behind `stream`: `socket`, or with `proxy_protocol` the one in
+    /// the PROXY header. `None` if the header is missing or invalid, the connection is then to
+    /// be closed.
+    async fn peer_address(
+        proxy_protocol: bool,
+        clock: Arc<dyn Clock>,
+        stream: &mut TcpStream,
+        socket: SocketAddr,
+    ) -> Option<SocketAddr> {
+        if !proxy_protocol {
+            return Some(socket);
+        }
+
+        let header = tokio::select! {
+            header = proxy::read_header(stream) => Some(header),
+            () = clock.sleep(PROXY_HEADER_TIMEOUT) => None,
+        };
+        match header {
+            Some(Ok(Some(peer))) => {
+                tracing::info!("Connection from {socket:?} is proxied for {peer:?}");
+                Some(peer)
+            }
+            Some(Ok(None)) => Some(socket),
+            Some(Err(e)) => {
+                tracing::warn!("Closing connection from {socket:?}, invalid PROXY header: {e:?}");
+                None
+            }
+            None => {
+                tracing::warn!("Closing connection from {socket:?}, no PROXY header in time");
+                None
+            }
+        }
+    }
+
+    /// Sets up an accepted connection in its own task, holding one of the setup permits until
+    /// done. The task yields the connection with its peer address, see `peer_address`.
+    fn spawn_setup(
         &self,
-        stream: tokio::net::TcpStream,
+        setups: &mut JoinSet<(TcpStream, Option<SocketAddr>)>,
+        mut stream: TcpStream,
         socket: SocketAddr,
-        encoder_sender: Sender<EncoderTaskControl>,
-        decoder_sender: Sender<DecoderTaskControl>,
+    ) {
+        let Ok(permit) = Arc::clone(&self.setup_permits).try_acquire_owned() else {
+            // Only the accept loop takes permits, and it checked there is one left
+            tracing::error!("No setup permit left, closing connection from {socket:?}");
+            return;
+        };
+        let proxy_protocol = self.proxy_protocol;
+        let clock = Arc::clone(&self.clock);
+        setups.spawn(async move {
+            let peer = Self::peer_address(proxy_protocol, clock, &mut stream, socket).await;
+            drop(permit);
+            (stream, peer)
+        });
+    }
+
+    fn encoder_has_capacity(&self, encoder_sender: &Sender<EncoderTaskControl>) -> bool {
+        self.min_encoder_capacity
+            .is_none_or(|min| encoder_sender.capacity() >= min)
+    }
+
+    fn allocate_client_id(&mut self, socket: SocketAddr) -> ClientId {
+        match self.client_id_allocation {
+            ClientIdAllocation::SourcePort => ClientId::from(socket.port()),
+            ClientIdAllocation::Sequential => loop {
+                let client_id = ClientId::from(self.next_client_id);
+                // 0 is skipped when wrapping around
+                self.next_client_id = self.next_client_id.checked_add(1).unwrap_or(1);
+                if !self.clients.contains_key(&client_id) {
+                    break client_id;
+                }
+            },
+        }
+    }
+
+    /// Sets the socket options for an accepted connection.
+    fn configure_socket(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+        let socket = socket2::SockRef::from(stream);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers the client with the decoder and the encoder, or with neither if the server is
+    /// cancelled first. In that case the stream is dropped, closing the connection.
+    async fn handle_new_client(
+        &mut self,
+        stream: TcpStream,
+        socket: SocketAddr,
+        encoder_sender: &Sender<EncoderTaskControl>,
+        decoder_sender: &Sender<DecoderTaskControl>,
+        cancellation_token: &CancellationToken,
     ) -> anyhow::Result<()> {
         tracing::info!("Accepted connection from: {:?}", socket);
-        match stream.writable().await {
-            Ok(()) => {}
-            Err(e) => {
-                tracing::error!("Failed to wait for stream to be writable: {:?}", e);
-                return Ok(());
-            }
+        // No need to wait for the stream to be writable, the encoder's writes do. A socket that
+        // broke in the meantime is noticed by the decoder and cleaned up as a disconnect.
+        if let Err(e) = self.configure_socket(&stream) {
+            tracing::warn!("Closing connection from {socket:?}, failed to configure socket: {e:?}");
+            return Ok(());
         }
-        stream.set_nodelay(true)?;
+        let client_id = self.allocate_client_id(socket);
+        if let Some(connected) = self.clients.get(&client_id) {
+            // Only possible with `SourcePort`, for peers on different hosts. The decoder and
+            // encoder would refuse it anyway, the connected client is left alone.
+            tracing::warn!(
+                "Closing connection from {socket:?}, {client_id:?} is already connected from {connected:?}"
+            );
+            return Ok(());
+        }
+
+        // Both slots are reserved before anything is sent, so a client is never half registered
+        let permits = tokio::select! {
+            biased;
+            () = cancellation_token.cancelled() => None,
+            permits = async {
+                let decoder_permit = decoder_sender
+                    .reserve()
+                    .await
+                    .context("Failed to send message to decoder")?;
+                let encoder_permit = encoder_sender
+                    .reserve()
+                    .await
+                    .context("Failed to send message to encoder")?;
+                anyhow::Ok((decoder_permit, encoder_permit))
+            } => Some(permits?),
+        };
+        let Some((decoder_permit, encoder_permit)) = permits else {
+            tracing::info!("Server cancelled, closing connection from {socket:?}");
+            return Ok(());
+        };
+
         let (read, write) = stream.into_split();
-        let client_id = ClientId(socket.port());
-        decoder_sender
-            .send(DecoderTaskControl::ClientAdded(client_id, read))
-            .await
-            .context("Failed to send message to decoder")?;
+        let (login_sent_sender, login_sent_receiver) = oneshot::channel();
+        decoder_permit.send(DecoderTaskControl::ClientAdded(
+            client_id,
+            read,
+            login_sent_receiver,
+        ));
+        encoder_permit.send(EncoderTaskControl::ClientAdded(
+            client_id,
+            write,
+            login_sent_sender,
+        ));
+        self.clients.insert(client_id, socket);
+        self.publish(ServerEvent::ClientConnected(client_id));
+
+        Ok(())
+    }
+
+    /// Applies the unknown product policy, queueing the rejection if there is one. Returns
+    /// whether the order should be processed.
+    fn check_product(
+        &mut self,
+        client_id: ClientId,
+        order: &Order,
+        outbound: &mut Vec<EncoderTaskControl>,
+    ) -> bool {
+        if self.products.contains(order.product) {
+            return true;
+        }
+
+        match self.unknown_product_policy {
+            UnknownProductPolicy::RejectWithNack => {
+                tracing::warn!("Rejecting order for unknown product from {client_id:?}: {order:?}");
+                outbound.push(EncoderTaskControl::Nack(
+                    client_id,
+                    RejectReason::UnknownProduct.into(),
+                ));
+                false
+            }
+            UnknownProductPolicy::SilentlyDrop => {
+                tracing::warn!("Dropping order for unknown product from {client_id:?}: {order:?}");
+                false
+            }
+            UnknownProductPolicy::AutoRegister => {
+                tracing::info!("Registering product {}", order.product);
+                self.products.register(order.product);
+                true
+            }
+        }
+    }
+
+    fn remove_client(&mut self, client_id: ClientId) {
+        if self.clients.remove(&client_id).is_some() {
+            self.publish(ServerEvent::ClientDisconnected(client_id));
+        }
+        if let Some(order_credits) = &mut self.order_credits {
+            order_credits.remove_client(client_id);
+        }
+        // Nobody is left to ack them
+        self.startup_queue
+            .retain(|(queued_for, _, _)| *queued_for != client_id);
+        let subscriptions = self
+            .subscriptions
+            .values_mut()
+            .chain(std::iter::once(&mut self.all_products_subscribers));
+        for subscribers in subscriptions {
+            if subscribers.contains(&client_id) {
+                Arc::make_mut(subscribers).remove(&client_id);
+            }
+        }
+        self.unfollowable.remove(&client_id);
+        self.followers.remove(&client_id);
+        for followers in self.followers.values_mut() {
+            if followers.contains(&client_id) {
+                Arc::make_mut(followers).remove(&client_id);
+            }
+        }
+    }
+
+    /// Makes `client_id` a follower of `followed`, unless `followed` is not connected or opted
+    /// out of being followed.
+    async fn follow(
+        &mut self,
+        client_id: ClientId,
+        followed: ClientId,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        if !self.clients.contains_key(&followed) || self.unfollowable.contains(&followed) {
+            tracing::warn!("{client_id:?} cannot follow {followed:?}");
+            encoder_sender
+                .send(EncoderTaskControl::Nack(
+                    client_id,
+                    RejectReason::NotFollowable.into(),
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        Arc::make_mut(self.followers.entry(followed).or_default()).insert(client_id);
         encoder_sender
-            .send(EncoderTaskControl::ClientAdded(client_id, write))
-            .await
-            .context("Failed to send message to encoder")?;
+            .send(EncoderTaskControl::FollowAck(
+                client_id,
+                FollowAck {
+                    client_id: followed,
+                },
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Tells the followers of `client_id` that its order traded `product`, if it has any.
+    fn followed_trade(&self, client_id: ClientId, product: Product) -> Option<EncoderTaskControl> {
+        let followers = Arc::clone(self.followers.get(&client_id)?);
+        if followers.is_empty() {
+            return None;
+        }
+
+        Some(EncoderTaskControl::BroadcastIf(
+            ClientFilter(Box::new(move |client_id| followers.contains(client_id))),
+            Box::new(FollowedTrade { client_id, product }),
+        ))
+    }
+
+    /// `conflate` is only passed on to the encoder, which does the conflating.
+    async fn set_subscription(
+        &mut self,
+        client_id: ClientId,
+        subscription: Subscription,
+        subscribed: bool,
+        conflate: bool,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        // Any well-formed product can be subscribed to, it may be registered later
+        let subscribers = Arc::make_mut(match subscription {
+            Subscription::AllProducts => &mut self.all_products_subscribers,
+            Subscription::Product(product) => self.subscriptions.entry(product).or_default(),
+        });
+        if subscribed {
+            subscribers.insert(client_id);
+        } else {
+            subscribers.remove(&client_id);
+        }
+        encoder_sender
+            .send(EncoderTaskControl::SubscriptionAck(
+                client_id,
+                SubscriptionAck {
+                    subscription,
+                    subscribed,
+                    conflate,
+                },
+            ))
+            .await?;
+        // The encoder handles its messages in order, so this reaches the client before any
+        // update published after the subscription. There is no single book to snapshot for `*`.
+        if let (true, Subscription::Product(product)) = (subscribed, subscription) {
+            let book = self.matcher.book_snapshot(product).unwrap_or_default();
+            encoder_sender
+                .send(EncoderTaskControl::Snapshot(
+                    client_id,
+                    Snapshot {
+                        product,
+                        buys: book.buys,
+                        sells: book.sells,
+                    },
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Accepts the clients subscribed to `product`, `None` when there are none.
+    fn subscribers(&self, product: Product) -> Option<ClientFilter> {
+        let subscribers = self
+            .subscriptions
+            .get(&product)
+            .cloned()
+            .unwrap_or_default();
+        let all_products_subscribers = Arc::clone(&self.all_products_subscribers);
+        if subscribers.is_empty() && all_products_subscribers.is_empty() {
+            return None;
+        }
+        Some(ClientFilter(Box::new(move |client_id| {
+            subscribers.contains(client_id) || all_products_subscribers.contains(client_id)
+        })))
+    }
+
+    fn imbalance_level(&self, imbalance: f64) -> usize {
+        self.imbalance_thresholds
+            .iter()
+            .filter(|&&threshold| imbalance >= threshold)
+            .count()
+    }
+
+    /// The imbalance of `product` to publish, if it crossed a threshold since it was last
+    /// published.
+    fn imbalance_update(&mut self, product: Product) -> Option<EncoderTaskControl> {
+        let value = self.matcher.imbalance(product)?;
+
+        let level = self.imbalance_level(value);
+        // A product starts out balanced
+        let balanced_level = self.imbalance_level(0.0);
+        let previous_level = self
+            .imbalance_levels
+            .insert(product, level)
+            .unwrap_or(balanced_level);
+        if level == previous_level {
+            return None;
+        }
+
+        let filter = self.subscribers(product)?;
+        Some(EncoderTaskControl::Imbalance(
+            filter,
+            Imbalance { product, value },
+        ))
+    }
+
+    /// Processes an order and sends the resulting frames, then hands its trade to the trade sink.
+    /// The frames are all worked out before the first is sent, so a failing send cannot leave the
+    /// order half processed. `decoded_at` is passed on with the ack, `None` for orders not read
+    /// from a client.
+    async fn handle_order(
+        &mut self,
+        client_id: ClientId,
+        order: Order,
+        decoded_at: Option<Instant>,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        let (outbound, execution) = self.process_order(client_id, &order, decoded_at);
+        let frames = outbound.len();
+        for (sent, message) in outbound.into_iter().enumerate() {
+            encoder_sender.send(message).await.with_context(|| {
+                format!("Sent {sent} of {frames} frames for {order:?} from {client_id:?}")
+            })?;
+        }
+        if let Some(execution) = execution {
+            self.trade_sink.publish(&execution).await;
+        }
+
+        Ok(())
+    }
+
+    /// Handles an order from a client, holding it back or rejecting it during the startup grace
+    /// period.
+    async fn handle_order_event(
+        &mut self,
+        client_id: ClientId,
+        order: Order,
+        decoded_at: Instant,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        // Events are handled before timers, so the grace period may be over already
+        if self
+            .ready_at
+            .is_some_and(|ready_at| self.clock.now() >= ready_at)
+        {
+            self.finish_startup(encoder_sender).await?;
+        }
+        if self.ready_at.is_none() {
+            return self
+                .handle_order(client_id, order, Some(decoded_at), encoder_sender)
+                .await;
+        }
+
+        match self.startup_order_handling {
+            StartupOrderHandling::Reject => {
+                tracing::warn!("Rejecting {order:?} from {client_id:?}, still starting");
+                encoder_sender
+                    .send(EncoderTaskControl::Nack(
+                        client_id,
+                        RejectReason::Starting.into(),
+                    ))
+                    .await?;
+            }
+            StartupOrderHandling::Queue => {
+                self.startup_queue.push((client_id, order, decoded_at));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn startup_due(&self) -> BoxFuture<'static, ()> {
+        match self.ready_at {
+            Some(ready_at) => self.clock.sleep_until(ready_at),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Ends the startup grace period, processing the orders queued during it.
+    async fn finish_startup(
+        &mut self,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        self.ready_at = None;
+        let queued = std::mem::take(&mut self.startup_queue);
+        tracing::info!(
+            "Startup grace period over, processing {} queued orders",
+            queued.len()
+        );
+        for (client_id, order, decoded_at) in queued {
+            self.handle_order(client_id, order, Some(decoded_at), encoder_sender)
+                .await?;
+        }
 
         Ok(())
     }
 
+    /// Applies `order` to the server state, returning the frames to send for it.
+    fn process_order(
+        &mut self,
+        client_id: ClientId,
+        order: &Order,
+        decoded_at: Option<Instant>,
+    ) -> (Vec<EncoderTaskControl>, Option<Execution>) {
+        let mut outbound = Vec::new();
+        if self.order_handling == OrderHandling::Echo {
+            let echo = Echo {
+                side: order.side,
+                product: order.product,
+            };
+            outbound.push(EncoderTaskControl::Echo(client_id, echo));
+            return (outbound, None);
+        }
+
+        if !self.check_product(client_id, order, &mut outbound) {
+            return (outbound, None);
+        }
+        if self.matcher.is_book_full(order) {
+            tracing::warn!("Books are full, rejecting order from {client_id:?}: {order:?}");
+            outbound.push(EncoderTaskControl::Nack(
+                client_id,
+                RejectReason::BookFull.into(),
+            ));
+            return (outbound, None);
+        }
+
+        // Taken before the duplicate check, so a rejected order does not burn its client order id
+        let credit = match &mut self.order_credits {
+            Some(order_credits) => {
+                let Some(credit) = order_credits.try_acquire(client_id) else {
+                    tracing::warn!("No credit left for {client_id:?}: {order:?}");
+                    outbound.push(EncoderTaskControl::Nack(
+                        client_id,
+                        RejectReason::NoCredit.into(),
+                    ));
+                    return (outbound, None);
+                };
+                Some(credit)
+            }
+            None => None,
+        };
+
+        if let Some(client_order_id) = &order.client_order_id {
+            let now = self.clock.now();
+            if !self
+                .recent_order_ids
+                .insert(now, client_id, client_order_id)
+            {
+                tracing::warn!("Duplicate client order id {client_order_id:?} from {client_id:?}");
+                outbound.push(EncoderTaskControl::Nack(
+                    client_id,
+                    RejectReason::Duplicate.into(),
+                ));
+                return (outbound, None);
+            }
+        }
+
+        outbound.push(EncoderTaskControl::OrderAck(
+            client_id,
+            OrderAck {
+                product: order.product,
+                client_order_id: order.client_order_id.clone(),
+            },
+            credit,
+            decoded_at,
+        ));
+
+        let trade_opt = self.matcher.add_order(order);
+
+        // Trades get their own control message, as clients may batch them
+        let execution = trade_opt.map(|entry| Execution {
+            product: order.product,
+            trade_id: entry.trade_id,
+            side: entry.side,
+            timestamp: self.clock.now() - self.started_at,
+            venue: order.venue,
+        });
+        if let Some(execution) = execution {
+            self.publish(ServerEvent::Trade(execution));
+            if let Some(filter) = self.subscribers(order.product) {
+                outbound.push(EncoderTaskControl::Trade(filter, execution));
+            }
+            // Owners of resting orders are not tracked, only the client completing the trade
+            outbound.extend(self.followed_trade(client_id, order.product));
+        }
+        // Imbalance is only published for the default venue
+        if order.venue.is_none() {
+            outbound.extend(self.imbalance_update(order.product));
+        }
+
+        (outbound, execution)
+    }
+
     // Mutable TODO
+    /// Handles `msg`, applying the send error policy if its frames could not be sent.
+    async fn dispatch_decoder_event(
+        &mut self,
+        msg: DecoderEvent,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        match self.handle_decoder_event(msg, encoder_sender).await {
+            Err(e) if self.send_error_policy == SendErrorPolicy::LogAndContinue => {
+                tracing::error!("Failed to handle decoder event, continuing: {e:?}");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Disconnects a client the decoder has dropped for `reason`, letting it know why.
+    async fn time_out_client(
+        &mut self,
+        client_id: ClientId,
+        reason: RejectReason,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        encoder_sender
+            .send(EncoderTaskControl::Nack(client_id, reason.into()))
+            .await?;
+        self.remove_client(client_id);
+        encoder_sender
+            .send(EncoderTaskControl::ClientDisconnected(client_id))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Applies the options kept by the server and passes `option` on to the encoder.
+    async fn set_option(
+        &mut self,
+        client_id: ClientId,
+        option: ClientOption,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        if option == ClientOption::NoFollow {
+            self.unfollowable.insert(client_id);
+            self.followers.remove(&client_id);
+        }
+        encoder_sender
+            .send(EncoderTaskControl::SetOption(client_id, option))
+            .await?;
+
+        Ok(())
+    }
+
     async fn handle_decoder_event(
         &mut self,
         msg: DecoderEvent,
         encoder_sender: &Sender<EncoderTaskControl>,
     ) -> anyhow::Result<()> {
+        // Still in flight when the server disconnected the client, e.g. at the end of a drain
+        if !self.clients.contains_key(&msg.client_id()) {
+            tracing::warn!("Dropping event from disconnected client: {msg:?}");
+            return Ok(());
+        }
         match msg {
             DecoderEvent::ClientDisconnected(client_id) => {
+                self.remove_client(client_id);
+
                 // forward the event
                 encoder_sender
                     .send(EncoderTaskControl::ClientDisconnected(client_id))
@@ -76,65 +1087,400 @@ impl Server {
 
                 Ok(())
             }
-            DecoderEvent::Order(client_id, order) => {
+            // The permit is released once the order is handled
+            DecoderEvent::Order(client_id, order, _permit, decoded_at) => {
+                self.handle_order_event(client_id, order, decoded_at, encoder_sender)
+                    .await
+            }
+            DecoderEvent::HandshakeTimeout(client_id) => {
+                self.time_out_client(client_id, RejectReason::HandshakeTimeout, encoder_sender)
+                    .await
+            }
+            DecoderEvent::IdleWarning(client_id) => {
+                encoder_sender
+                    .send(EncoderTaskControl::IdleWarning(client_id))
+                    .await?;
+
+                Ok(())
+            }
+            DecoderEvent::IdleTimeout(client_id) => {
+                self.time_out_client(client_id, RejectReason::IdleTimeout, encoder_sender)
+                    .await
+            }
+            DecoderEvent::Options(client_id, option) => {
+                self.set_option(client_id, option, encoder_sender).await
+            }
+            DecoderEvent::Subscribe(client_id, subscription, conflate) => {
+                self.set_subscription(client_id, subscription, true, conflate, encoder_sender)
+                    .await
+            }
+            DecoderEvent::Unsubscribe(client_id, subscription) => {
+                self.set_subscription(client_id, subscription, false, false, encoder_sender)
+                    .await
+            }
+            DecoderEvent::Tape(client_id, product, count) => {
+                let tape = Tape {
+                    product,
+                    trades: self.matcher.tape(product, count),
+                };
+                encoder_sender
+                    .send(EncoderTaskControl::Tape(client_id, tape))
+                    .await?;
+
+                Ok(())
+            }
+            DecoderEvent::Ping(client_id) => {
+                // Skips everything else, it is only there to show the server is alive
+                encoder_sender
+                    .send(EncoderTaskControl::Pong(client_id))
+                    .await?;
+
+                Ok(())
+            }
+            DecoderEvent::CrcMismatch(client_id) => {
                 encoder_sender
-                    .send(EncoderTaskControl::OrderAck(
+                    .send(EncoderTaskControl::Nack(
                         client_id,
-                        OrderAck {
-                            product: order.product,
-                        },
+                        RejectReason::BadCrc.into(),
                     ))
                     .await?;
 
-                let trade_opt = match order.side {
-                    Side::Buy => self.matcher.add_buy(order.product),
-                    Side::Sell => self.matcher.add_sell(order.product),
+                Ok(())
+            }
+            DecoderEvent::Follow(client_id, followed) => {
+                self.follow(client_id, followed, encoder_sender).await
+            }
+            DecoderEvent::QueueDepth(client_id) => {
+                encoder_sender
+                    .send(EncoderTaskControl::QueueDepth(client_id))
+                    .await?;
+
+                Ok(())
+            }
+            DecoderEvent::Info(client_id) => {
+                let info = Info {
+                    version: env!("CARGO_PKG_VERSION"),
+                    uptime: self.clock.now() - self.started_at,
+                    clients: self.clients.len(),
                 };
+                encoder_sender
+                    .send(EncoderTaskControl::Info(client_id, info))
+                    .await?;
 
-                if let Some(t) = trade_opt {
-                    encoder_sender.send(EncoderTaskControl::Match(t)).await?;
-                }
+                Ok(())
+            }
+            DecoderEvent::Message(message) => {
+                encoder_sender
+                    .send(EncoderTaskControl::MessageAck(message.origin_client_id))
+                    .await?;
+                encoder_sender
+                    .send(EncoderTaskControl::Message(message))
+                    .await?;
 
                 Ok(())
             }
         }
     }
 
+    /// Runs until `cancellation_token` is cancelled, then shuts down in two phases: accepting
+    /// stops and the decoder is closed, every decoder event still in flight is handled, and only
+    /// then `encoder_flush_token` tells the encoder to flush the resulting frames and stop.
     pub async fn run(
         &mut self,
         encoder_sender: Sender<EncoderTaskControl>,
         decoder_sender: Sender<DecoderTaskControl>,
         mut decoder_event_receiver: Receiver<DecoderEvent>,
         cancellation_token: CancellationToken,
+        encoder_flush_token: CancellationToken,
     ) -> anyhow::Result<()> {
         tracing::info!("Server started");
+        let result = self
+            .accept_loop(
+                &encoder_sender,
+                decoder_sender,
+                &mut decoder_event_receiver,
+                &cancellation_token,
+            )
+            .await;
+
+        if result.is_ok() {
+            while let Some(msg) = decoder_event_receiver.recv().await {
+                if encoder_sender.is_closed() {
+                    // Already reported, handling the rest would only fail once per event
+                    tracing::warn!("Encoder stopped, dropping the remaining decoder events");
+                    break;
+                }
+                self.dispatch_decoder_event(msg, &encoder_sender).await?;
+            }
+            tracing::info!("Server drained decoder events");
+        }
+
+        encoder_flush_token.cancel();
+        result
+    }
+
+    /// Like `run`, but first feeds the requests in `source` through the server, as if a client
+    /// had sent them. Only orders are replayed, and their acks go to `REPLAY_CLIENT_ID`, which
+    /// no client is ever given. Useful to load a known book, or to check the matcher against a
+    /// recorded session.
+    ///
+    /// `SNAPSHOT:` lines in `source`, as sent to subscribers, are checkpoints: the replayed book
+    /// must match them, or the replay fails rather than serving a wrong book. Ending a recorded
+    /// session with the snapshots of its books catches entries lost or corrupted on the way.
+    pub async fn run_with_source<R: AsyncRead + Unpin + Send>(
+        &mut self,
+        source: R,
+        encoder_sender: Sender<EncoderTaskControl>,
+        decoder_sender: Sender<DecoderTaskControl>,
+        decoder_event_receiver: Receiver<DecoderEvent>,
+        cancellation_token: CancellationToken,
+        encoder_flush_token: CancellationToken,
+    ) -> anyhow::Result<()> {
+        if let Err(e) = self.replay(source, &encoder_sender).await {
+            encoder_flush_token.cancel();
+            return Err(e.context("Failed to replay source"));
+        }
+
+        self.run(
+            encoder_sender,
+            decoder_sender,
+            decoder_event_receiver,
+            cancellation_token,
+            encoder_flush_token,
+        )
+        .await
+    }
+
+    async fn replay<R: AsyncRead + Unpin + Send>(
+        &mut self,
+        mut source: R,
+        encoder_sender: &Sender<EncoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        let mut codec = TextCodec::default();
+        let mut buffer = BytesMut::new();
+        let mut eof = false;
+        let mut replayed = 0;
         loop {
+            // Lines are taken off the front, so the buffer always starts with a whole line
+            if buffer.starts_with(SNAPSHOT_PREFIX) {
+                if let Some(line) = split_line(&mut buffer, eof) {
+                    self.check_snapshot(&line)?;
+                    continue;
+                }
+            }
+            let decoded = if eof {
+                codec.decode_eof(&mut buffer)
+            } else {
+                codec.decode(&mut buffer)
+            };
+            match decoded {
+                Ok(Some(Request::Order(order))) => {
+                    self.handle_order(REPLAY_CLIENT_ID, order, None, encoder_sender)
+                        .await?;
+                    replayed += 1;
+                    continue;
+                }
+                Ok(Some(request)) => {
+                    tracing::warn!(
+                        "Skipping replayed request, only orders are replayed: {request:?}"
+                    );
+                    continue;
+                }
+                Ok(None) if eof => break,
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!("Skipping invalid replayed request: {e:?}");
+                    continue;
+                }
+            }
+
+            eof = source.read_buf(&mut buffer).await? == 0;
+        }
+        tracing::info!("Replayed {replayed} orders");
+
+        Ok(())
+    }
+
+    /// Fails if the book of a replayed `SNAPSHOT:` line differs from the matcher's.
+    fn check_snapshot(&self, line: &[u8]) -> anyhow::Result<()> {
+        let line = std::str::from_utf8(line)?;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let expected: Snapshot = line.parse()?;
+        let book = self
+            .matcher
+            .book_snapshot(expected.product)
+            .unwrap_or_default();
+        let replayed = Snapshot {
+            product: expected.product,
+            buys: book.buys,
+            sells: book.sells,
+        };
+        anyhow::ensure!(
+            replayed == expected,
+            "Replayed book diverges from the source: {replayed:?}, expected {expected:?}"
+        );
+        tracing::info!("Replayed book matches {expected:?}");
+
+        Ok(())
+    }
+
+    /// Resolves with the next admin command. Pending forever without an admin channel, or once
+    /// it is closed.
+    async fn next_admin_command(receiver: &mut Option<Receiver<AdminCommand>>) -> AdminCommand {
+        if let Some(admin_receiver) = receiver {
+            if let Some(command) = admin_receiver.recv().await {
+                return command;
+            }
+            tracing::info!("Admin channel closed");
+            *receiver = None;
+        }
+
+        std::future::pending().await
+    }
+
+    async fn handle_admin_command(
+        &mut self,
+        command: AdminCommand,
+        decoder_sender: &Sender<DecoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        match command {
+            AdminCommand::Drain(deadline) => {
+                tracing::warn!(
+                    "Draining, disconnecting {} clients in {deadline:?}",
+                    self.clients.len()
+                );
+                self.draining = true;
+                self.drain_deadline = Some(self.clock.now() + deadline);
+            }
+            AdminCommand::PauseDecoding => {
+                tracing::warn!("Pausing decoding");
+                decoder_sender.send(DecoderTaskControl::Pause).await?;
+            }
+            AdminCommand::ResumeDecoding => {
+                tracing::info!("Resuming decoding");
+                decoder_sender.send(DecoderTaskControl::Resume).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn drain_due(&self) -> BoxFuture<'static, ()> {
+        match self.drain_deadline {
+            Some(deadline) => self.clock.sleep_until(deadline),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Says `BYE` to every client still connected at the end of a drain and disconnects them.
+    async fn finish_drain(
+        &mut self,
+        encoder_sender: &Sender<EncoderTaskControl>,
+        decoder_sender: &Sender<DecoderTaskControl>,
+    ) -> anyhow::Result<()> {
+        self.drain_deadline = None;
+        let clients: Vec<ClientId> = self.clients.keys().copied().collect();
+        tracing::warn!(
+            "Drain deadline reached, disconnecting {} clients",
+            clients.len()
+        );
+        for client_id in clients {
+            encoder_sender
+                .send(EncoderTaskControl::Bye(client_id))
+                .await?;
+            self.remove_client(client_id);
+            encoder_sender
+                .send(EncoderTaskControl::ClientDisconnected(client_id))
+                .await?;
+            // Otherwise a client ignoring the `BYE` could keep sending requests
+            decoder_sender
+                .send(DecoderTaskControl::RemoveClient(client_id))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn accept_loop(
+        &mut self,
+        encoder_sender: &Sender<EncoderTaskControl>,
+        decoder_sender: Sender<DecoderTaskControl>,
+        decoder_event_receiver: &mut Receiver<DecoderEvent>,
+        cancellation_token: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut accept_paused = false;
+        // Dropped on return, which closes the connections still being set up
+        let mut setups = JoinSet::new();
+        loop {
+            let accepting = self.encoder_has_capacity(encoder_sender);
+            if accepting == accept_paused {
+                accept_paused = !accepting;
+                if accept_paused {
+                    tracing::warn!("Encoder is backed up, pausing accept");
+                } else {
+                    tracing::info!("Encoder drained, resuming accept");
+                }
+            }
+
+            let drain_due = self.drain_due();
+            let startup_due = self.startup_due();
             tracing::info!("Waiting for connection...");
             tokio::select! {
                 biased;
+                // Nothing can be sent to clients anymore, so stop instead of failing every event
+                () = encoder_sender.closed() => {
+                    tracing::error!("Encoder stopped while the server was running, shutting down");
+                    return Ok(());
+                }
                 decoder_event = decoder_event_receiver.recv() => {
                     match decoder_event {
                         None => {},
                         Some(msg) => {
-                            self.handle_decoder_event(msg, &encoder_sender).await?;
+                            self.dispatch_decoder_event(msg, encoder_sender).await?;
                         }
                     }
 
                 }
                 () = cancellation_token.cancelled() => {
+                    // Dropping `decoder_sender` stops the decoder, which closes its event channel
                     tracing::info!("Server cancelled");
                     return Ok(());
                 }
-                client = self.listener.accept() => {
-                    match client {
-                        Ok((stream, socket)) => {
-                            match self.handle_new_client(stream, socket, encoder_sender.clone(), decoder_sender.clone()).await {
-                                Ok(()) => {}
-                                Err(e) => {
-                                    tracing::error!("Failed to handle new client: {e:?}");
-                                }
-                            };
+                command = Self::next_admin_command(&mut self.admin_receiver) => {
+                    self.handle_admin_command(command, &decoder_sender).await?;
+                }
+                () = drain_due => {
+                    self.finish_drain(encoder_sender, &decoder_sender).await?;
+                }
+                () = startup_due => {
+                    self.finish_startup(encoder_sender).await?;
+                }
+                // Nothing else wakes us up when the encoder drains, so poll while paused
+                () = tokio::time::sleep(BACKPRESSURE_RECHECK_INTERVAL), if !accepting => {}
+                Some(setup) = setups.join_next(), if !setups.is_empty() => {
+                    let (stream, socket) = match setup {
+                        Ok((stream, Some(socket))) => (stream, socket),
+                        Ok((_, None)) => continue,
+                        Err(e) => {
+                            tracing::error!("Connection setup failed: {e:?}");
+                            continue;
+                        }
+                    };
+                    if let Some(nack) = self.reject_connection() {
+                        self.close_rejected(stream, socket, nack).await;
+                        continue;
+                    }
+                    match self.handle_new_client(stream, socket, encoder_sender, &decoder_sender, cancellation_token).await {
+                        Ok(()) => {}
+                        Err(e) => {
+                            tracing::error!("Failed to handle new client: {e:?}");
                         }
+                    };
+                }
+                // Connections over the setup limit wait in the listen backlog
+                client = self.listener.accept(), if accepting && self.setup_permits.available_permits() > 0 => {
+                    match client {
+                        Ok((stream, socket)) => self.spawn_setup(&mut setups, stream, socket),
                         Err(e) => {
                             tracing::error!("Failed to accept connection: {e:?}");
                         }
@@ -144,3 +1490,468 @@ impl Server {
         }
     }
 }
+
+/// Takes the first line off `buffer`, without its newline. A last line without one is only
+/// taken at `eof`.
+fn split_line(buffer: &mut BytesMut, eof: bool) -> Option<BytesMut> {
+    match buffer.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = buffer.split_to(end);
+            buffer.advance(1);
+            Some(line)
+        }
+        None if eof => Some(buffer.split()),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    /// Registers a client as if it had connected, so the server handles its events.
+    fn add_client(server: &mut Server, client_id: ClientId) {
+        server
+            .clients
+            .insert(client_id, SocketAddr::from(([127, 0, 0, 1], 0)));
+    }
+
+    #[tokio::test]
+    async fn test_socket_buffer_sizes_are_applied() {
+        const SIZE: usize = 256 * 1024;
+        let config = ServerConfig {
+            send_buffer_size: Some(SIZE),
+            recv_buffer_size: Some(SIZE),
+            ..ServerConfig::default()
+        };
+        let server = Server::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let _client = TcpStream::connect(server.listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = server.listener.accept().await.unwrap();
+
+        let default_size = socket2::SockRef::from(&stream).send_buffer_size().unwrap();
+        server.configure_socket(&stream).unwrap();
+
+        // The OS may round the sizes up, e.g. Linux doubles them
+        let socket = socket2::SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.send_buffer_size().unwrap() >= SIZE);
+        assert!(socket.recv_buffer_size().unwrap() >= SIZE);
+        assert_ne!(socket.send_buffer_size().unwrap(), default_size);
+    }
+
+    #[tokio::test]
+    async fn test_empty_product_registry_is_refused() {
+        let config = ServerConfig {
+            products: ProductRegistry::new([]),
+            ..ServerConfig::default()
+        };
+        let error = Server::bind_with_config("127.0.0.1:0", config.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ServerError>(),
+            Some(&ServerError::NoProducts)
+        );
+
+        // Products registered on the fly make up for it
+        let config = ServerConfig {
+            unknown_product_policy: UnknownProductPolicy::AutoRegister,
+            ..config
+        };
+        assert!(Server::bind_with_config("127.0.0.1:0", config)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_taken_address_is_reported() {
+        let server = Server::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap();
+
+        let error = Server::bind(address).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ServerError>(),
+            Some(&ServerError::AddressInUse)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_during_accept_leaves_no_orphaned_clients() {
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let (encoder_sender, mut encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let (decoder_sender, mut decoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let (decoder_event_sender, decoder_event_receiver) = tokio::sync::mpsc::channel(1);
+        let cancellation_token = CancellationToken::new();
+
+        // Stand-ins for the encoder and decoder, recording who was registered with them
+        let encoder = tokio::spawn(async move {
+            let mut clients = HashSet::new();
+            while let Some(message) = encoder_receiver.recv().await {
+                if let EncoderTaskControl::ClientAdded(client_id, _, _) = message {
+                    clients.insert(client_id);
+                }
+            }
+            clients
+        });
+        let decoder = tokio::spawn(async move {
+            let mut clients = HashSet::new();
+            while let Some(DecoderTaskControl::ClientAdded(client_id, _, _)) =
+                decoder_receiver.recv().await
+            {
+                clients.insert(client_id);
+            }
+            clients
+        });
+        let hammer = tokio::spawn(async move {
+            while TcpStream::connect(addr).await.is_ok() {
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let canceller = cancellation_token.clone();
+        let cancel = async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+            // Lets the server finish draining decoder events
+            drop(decoder_event_sender);
+        };
+        let (result, ()) = tokio::join!(
+            server.run(
+                encoder_sender,
+                decoder_sender,
+                decoder_event_receiver,
+                cancellation_token,
+                CancellationToken::new(),
+            ),
+            cancel,
+        );
+        result.unwrap();
+        hammer.abort();
+
+        let encoder_clients = encoder.await.unwrap();
+        let decoder_clients = decoder.await.unwrap();
+        let server_clients: HashSet<ClientId> = server.clients.keys().copied().collect();
+        assert!(!server_clients.is_empty());
+        assert_eq!(encoder_clients, server_clients);
+        assert_eq!(decoder_clients, server_clients);
+    }
+
+    #[tokio::test]
+    async fn test_replay_checks_the_books_against_snapshots() {
+        const SOURCE: &str = "BUY:APPLE\nBUY:APPLE\nSELL:PEAR\n\
+                              SNAPSHOT:APPLE:buys=2,sells=0\nSNAPSHOT:PEAR:buys=0,sells=1";
+        let (encoder_sender, _encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        server
+            .replay(SOURCE.as_bytes(), &encoder_sender)
+            .await
+            .unwrap();
+
+        // A corrupted entry leaves a different book than the one recorded at the end
+        let corrupted = SOURCE.replacen("BUY:APPLE", "SELL:APPLE", 1);
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        let error = server
+            .replay(corrupted.as_bytes(), &encoder_sender)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("diverges"),
+            "Unexpected error: {error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_addr_is_known_while_connected() {
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        let (encoder_sender, mut encoder_receiver) = tokio::sync::mpsc::channel(1);
+        let (decoder_sender, _decoder_receiver) = tokio::sync::mpsc::channel(1);
+
+        let client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, socket) = server.listener.accept().await.unwrap();
+        server
+            .handle_new_client(
+                stream,
+                socket,
+                &encoder_sender,
+                &decoder_sender,
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let Some(EncoderTaskControl::ClientAdded(client_id, _, _)) = encoder_receiver.recv().await
+        else {
+            panic!("Expected the client to be added");
+        };
+
+        let peer = server.peer_addr(client_id).unwrap();
+        assert!(peer.ip().is_loopback());
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        server
+            .handle_decoder_event(DecoderEvent::ClientDisconnected(client_id), &encoder_sender)
+            .await
+            .unwrap();
+        assert_eq!(server.peer_addr(client_id), None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_client_id_is_closed() {
+        let (events, mut event_receiver) = broadcast::channel(u8::MAX as usize);
+        let mut server = Server::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_events(events);
+        let (encoder_sender, mut encoder_receiver) = tokio::sync::mpsc::channel(2);
+        let (decoder_sender, _decoder_receiver) = tokio::sync::mpsc::channel(2);
+
+        // Peers on different hosts can share a source port, and so a `SourcePort` client id
+        let first = tokio::net::TcpSocket::new_v4().unwrap();
+        first.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = tokio::net::TcpSocket::new_v4().unwrap();
+        second
+            .bind(SocketAddr::from(([127, 0, 0, 2], port)))
+            .unwrap();
+        let first = first.connect(server.local_addr().unwrap()).await.unwrap();
+        let mut second = second.connect(server.local_addr().unwrap()).await.unwrap();
+        for _ in 0..2 {
+            let (stream, socket) = server.listener.accept().await.unwrap();
+            server
+                .handle_new_client(
+                    stream,
+                    socket,
+                    &encoder_sender,
+                    &decoder_sender,
+                    &CancellationToken::new(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let client_id = ClientId::from(port);
+        assert!(matches!(
+            encoder_receiver.try_recv(),
+            Ok(EncoderTaskControl::ClientAdded(id, ..)) if id == client_id
+        ));
+        assert!(encoder_receiver.try_recv().is_err());
+        assert_eq!(
+            server.peer_addr(client_id),
+            Some(first.local_addr().unwrap())
+        );
+        assert!(matches!(
+            event_receiver.try_recv(),
+            Ok(ServerEvent::ClientConnected(id)) if id == client_id
+        ));
+        assert!(event_receiver.try_recv().is_err());
+        assert_eq!(second.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_orders_over_credit_are_rejected_until_acked() {
+        let config = ServerConfig {
+            max_in_flight_orders: Some(1),
+            ..ServerConfig::default()
+        };
+        let mut server = Server::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let (encoder_sender, mut encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let client_id = ClientId(1);
+        add_client(&mut server, client_id);
+        let order = || {
+            DecoderEvent::Order(
+                client_id,
+                "BUY:APPLE".parse().unwrap(),
+                None,
+                Instant::now(),
+            )
+        };
+
+        server
+            .handle_decoder_event(order(), &encoder_sender)
+            .await
+            .unwrap();
+        server
+            .handle_decoder_event(order(), &encoder_sender)
+            .await
+            .unwrap();
+
+        let first_ack = encoder_receiver.recv().await.unwrap();
+        assert!(matches!(first_ack, EncoderTaskControl::OrderAck(..)));
+        assert!(matches!(
+            encoder_receiver.recv().await.unwrap(),
+            EncoderTaskControl::Nack(
+                _,
+                Nack {
+                    reason: RejectReason::NoCredit,
+                    ..
+                }
+            )
+        ));
+
+        // The encoder is done with the ack, which returns the credit
+        drop(first_ack);
+        server
+            .handle_decoder_event(order(), &encoder_sender)
+            .await
+            .unwrap();
+        assert!(matches!(
+            encoder_receiver.recv().await.unwrap(),
+            EncoderTaskControl::OrderAck(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_full_books_only_take_matching_orders() {
+        let mut server = Server::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_matcher(Matcher::new().with_max_resting_orders(2));
+        add_client(&mut server, ClientId(1));
+        let (encoder_sender, mut encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let mut send_order = async |order: &str| {
+            server
+                .handle_decoder_event(
+                    DecoderEvent::Order(ClientId(1), order.parse().unwrap(), None, Instant::now()),
+                    &encoder_sender,
+                )
+                .await
+                .unwrap();
+            encoder_receiver.recv().await.unwrap()
+        };
+
+        for order in ["BUY:APPLE", "BUY:PEAR"] {
+            assert!(matches!(
+                send_order(order).await,
+                EncoderTaskControl::OrderAck(..)
+            ));
+        }
+        // Resting either side of an existing book, or in a new one, is refused
+        for order in ["BUY:APPLE", "SELL:TOMATO"] {
+            assert!(matches!(
+                send_order(order).await,
+                EncoderTaskControl::Nack(
+                    _,
+                    Nack {
+                        reason: RejectReason::BookFull,
+                        ..
+                    }
+                )
+            ));
+        }
+        // Matching still works, and frees up room
+        assert!(matches!(
+            send_order("SELL:APPLE").await,
+            EncoderTaskControl::OrderAck(..)
+        ));
+        assert!(matches!(
+            send_order("SELL:TOMATO").await,
+            EncoderTaskControl::OrderAck(..)
+        ));
+    }
+
+    /// Collects the logs written while it is the default subscriber.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_encoder_stops_the_server_with_one_error() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        add_client(&mut server, ClientId(1));
+        let (encoder_sender, encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let (decoder_sender, _decoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        let (decoder_event_sender, decoder_event_receiver) =
+            tokio::sync::mpsc::channel(u8::MAX as usize);
+        drop(encoder_receiver);
+        for _ in 0..10 {
+            decoder_event_sender
+                .send(DecoderEvent::Info(ClientId(1)))
+                .await
+                .unwrap();
+        }
+        drop(decoder_event_sender);
+
+        let encoder_flush_token = CancellationToken::new();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            server.run(
+                encoder_sender,
+                decoder_sender,
+                decoder_event_receiver,
+                CancellationToken::new(),
+                encoder_flush_token.clone(),
+            ),
+        )
+        .await
+        .expect("Server kept running without an encoder")
+        .unwrap();
+        assert!(encoder_flush_token.is_cancelled());
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let errors: Vec<_> = logs.lines().filter(|line| line.contains("ERROR")).collect();
+        assert_eq!(errors.len(), 1, "{logs}");
+        assert!(errors[0].contains("Encoder stopped"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_closed_encoder_channel_is_logged_and_skipped() {
+        let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+        add_client(&mut server, ClientId(1));
+        let (encoder_sender, encoder_receiver) = tokio::sync::mpsc::channel(u8::MAX as usize);
+        drop(encoder_receiver);
+        let order = |side| {
+            DecoderEvent::Order(
+                ClientId(1),
+                format!("{side}:APPLE").parse().unwrap(),
+                None,
+                Instant::now(),
+            )
+        };
+
+        // Both orders still reach the matcher, even though their acks and trade are lost
+        server
+            .dispatch_decoder_event(order("BUY"), &encoder_sender)
+            .await
+            .unwrap();
+        server
+            .dispatch_decoder_event(order("SELL"), &encoder_sender)
+            .await
+            .unwrap();
+        assert_eq!(server.matcher.tape(Product::Apples, 10).len(), 1);
+
+        server.send_error_policy = SendErrorPolicy::Stop;
+        assert!(server
+            .dispatch_decoder_event(order("BUY"), &encoder_sender)
+            .await
+            .is_err());
+    }
+}
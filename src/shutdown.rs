@@ -0,0 +1,93 @@
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Returns a token cancelled on the first SIGINT or SIGTERM, for graceful shutdown.
+///
+/// The process exits right away on the next one, for when the graceful shutdown hangs. The
+/// handlers are installed before this returns, so signals sent afterwards are never missed.
+/// Must be called from within a tokio runtime. Only Ctrl-C is handled outside Unix.
+pub fn listen() -> anyhow::Result<CancellationToken> {
+    let token = CancellationToken::new();
+    let mut signals = Signals::new()?;
+    let cancelled = token.clone();
+    tokio::spawn(async move {
+        let (name, _) = signals.recv().await;
+        tracing::warn!("{name} received, shutting down");
+        cancelled.cancel();
+
+        let (name, exit_code) = signals.recv().await;
+        tracing::error!("{name} received during shutdown, exiting");
+        std::process::exit(exit_code);
+    });
+
+    Ok(token)
+}
+
+/// The signals asking the process to stop.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: Signal,
+    #[cfg(unix)]
+    terminate: Signal,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unnecessary_wraps)]
+    const fn new() -> std::io::Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Waits for the next signal, returning its name and the exit code of a process killed by
+    /// it.
+    #[cfg(unix)]
+    async fn recv(&mut self) -> (&'static str, i32) {
+        tokio::select! {
+            _ = self.interrupt.recv() => ("SIGINT", 130),
+            _ = self.terminate.recv() => ("SIGTERM", 143),
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> (&'static str, i32) {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {e:?}");
+            std::future::pending::<()>().await;
+        }
+        ("Ctrl-C", 130)
+    }
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sigterm_cancels_the_token() {
+        let token = listen().unwrap();
+        assert!(!token.is_cancelled());
+
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .expect("Failed to run kill");
+        assert!(status.success());
+
+        tokio::time::timeout(Duration::from_secs(1), token.cancelled())
+            .await
+            .expect("Token not cancelled by SIGTERM");
+    }
+}
@@ -0,0 +1,106 @@
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::models::{Encode, Execution};
+
+/// Longest `EXEC:` frame, with the longest product and venue.
+const MAX_EXECUTION_LEN: usize = 128;
+
+/// Destination for every trade the server executes, e.g. a message bus for downstream systems.
+///
+/// The server awaits `publish` after sending a trade's frames to the clients, one trade at a
+/// time in the order they happened. A slow sink holds up order handling, so sinks that talk to
+/// the network should queue trades and send them from their own task. Failures are the sink's
+/// to log or retry, the server does not hear about them.
+pub trait TradeSink: std::fmt::Debug + Send + Sync {
+    fn publish<'a>(&'a self, trade: &'a Execution) -> BoxFuture<'a, ()>;
+}
+
+/// Drops every trade, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopTradeSink;
+
+impl TradeSink for NoopTradeSink {
+    fn publish<'a>(&'a self, _trade: &'a Execution) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Writes every trade as an `EXEC:` line to `W`, e.g. a file opened with `tokio::fs::File`.
+#[derive(Debug)]
+pub struct WriterTradeSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> WriterTradeSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl WriterTradeSink<tokio::io::Stdout> {
+    #[must_use]
+    pub fn stdout() -> Self {
+        Self::new(tokio::io::stdout())
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + std::fmt::Debug> TradeSink for WriterTradeSink<W> {
+    fn publish<'a>(&'a self, trade: &'a Execution) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut buffer = [0; MAX_EXECUTION_LEN];
+            let length = match trade.encode(&mut buffer) {
+                Ok(length) => length,
+                Err(e) => {
+                    tracing::error!("Failed to encode {trade:?} for the trade sink: {e:?}");
+                    return;
+                }
+            };
+            let mut writer = self.writer.lock().await;
+            // Flushed every time, so nothing is lost when the server is killed
+            let result = match writer.write_all(&buffer[..length]).await {
+                Ok(()) => writer.flush().await,
+                Err(e) => Err(e),
+            };
+            drop(writer);
+            if let Err(e) = result {
+                tracing::error!("Failed to write {trade:?} to the trade sink: {e:?}");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::models::{Product, Side};
+
+    #[tokio::test]
+    async fn test_writer_trade_sink_writes_exec_lines() {
+        let sink = WriterTradeSink::new(Vec::new());
+        for trade_id in 1..=2 {
+            let trade = Execution {
+                product: Product::Apples,
+                trade_id,
+                side: Side::Sell,
+                timestamp: Duration::from_millis(5),
+                venue: None,
+            };
+            sink.publish(&trade).await;
+        }
+
+        assert_eq!(
+            sink.writer.into_inner(),
+            b"EXEC:product=APPLE,id=1,qty=1,side=SELL,ts=5\n\
+              EXEC:product=APPLE,id=2,qty=1,side=SELL,ts=5\n"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Property tests for the text codec: parsing arbitrary client input and encoding arbitrary
+//! model values must never panic, whatever the buffer size.
+
+use std::time::Duration;
+
+use proptest::prelude::*;
+use single_thread_async_server::models::{
+    ClientId, ClientOption, Echo, Encode, Execution, FollowAck, FollowedTrade, Imbalance, Info,
+    Login, Message, Nack, Order, OrderAck, Product, QueueDepth, RejectReason, Request, Side,
+    Snapshot, Subscription, SubscriptionAck, Tape, TapeEntry, Trade, Trades, VenueId,
+    MAX_CLIENT_ORDER_ID_LEN,
+};
+
+fn product() -> impl Strategy<Value = Product> {
+    prop_oneof![
+        prop::sample::select(Product::BUILTIN.to_vec()),
+        "[A-Z0-9_]{1,16}".prop_map(|name| name.parse().unwrap()),
+    ]
+}
+
+fn venue() -> impl Strategy<Value = Option<VenueId>> {
+    prop::option::of("[A-Z0-9_]{1,16}".prop_map(|name| name.parse().unwrap()))
+}
+
+fn trade() -> impl Strategy<Value = Trade> {
+    (product(), venue()).prop_map(|(product, venue)| Trade { product, venue })
+}
+
+fn subscription() -> impl Strategy<Value = Subscription> {
+    prop_oneof![
+        Just(Subscription::AllProducts),
+        product().prop_map(Subscription::Product),
+    ]
+}
+
+fn side() -> impl Strategy<Value = Side> {
+    prop_oneof![Just(Side::Buy), Just(Side::Sell)]
+}
+
+fn client_order_id() -> impl Strategy<Value = Option<String>> {
+    // Anything but the attribute separator and line breaks
+    prop::option::of(
+        "[^@\r\n]{1,64}".prop_filter("Client order ids are limited in bytes", |id| {
+            id.len() <= MAX_CLIENT_ORDER_ID_LEN
+        }),
+    )
+}
+
+fn order() -> impl Strategy<Value = Order> {
+    (side(), product(), client_order_id(), venue()).prop_map(
+        |(side, product, client_order_id, venue)| Order {
+            side,
+            product,
+            client_order_id,
+            venue,
+        },
+    )
+}
+
+fn reject_reason() -> impl Strategy<Value = RejectReason> {
+    prop::sample::select(vec![
+        RejectReason::UnknownProduct,
+        RejectReason::Duplicate,
+        RejectReason::HandshakeTimeout,
+        RejectReason::IdleTimeout,
+        RejectReason::BookFull,
+        RejectReason::RateLimited,
+        RejectReason::Overloaded,
+        RejectReason::NoCredit,
+        RejectReason::Draining,
+        RejectReason::Starting,
+        RejectReason::NotFollowable,
+        RejectReason::Quota,
+        RejectReason::BadCrc,
+    ])
+}
+
+fn duration() -> impl Strategy<Value = Duration> {
+    (any::<u64>(), 0..1_000_000_000u32).prop_map(|(secs, nanos)| Duration::new(secs, nanos))
+}
+
+/// Every frame the server sends, with arbitrary contents.
+fn frame() -> impl Strategy<Value = Box<dyn Encode>> {
+    prop_oneof![
+        any::<u16>().prop_map(|id| Box::new(Login {
+            client_id: ClientId(id)
+        }) as Box<dyn Encode>),
+        (any::<u16>(), any::<String>()).prop_map(|(id, message)| Box::new(Message {
+            origin_client_id: ClientId(id),
+            message,
+        }) as Box<dyn Encode>),
+        (subscription(), any::<bool>(), any::<bool>()).prop_map(
+            |(subscription, subscribed, conflate)| Box::new(SubscriptionAck {
+                subscription,
+                subscribed,
+                conflate,
+            }) as Box<dyn Encode>
+        ),
+        (product(), client_order_id()).prop_map(|(product, client_order_id)| Box::new(OrderAck {
+            product,
+            client_order_id,
+        })
+            as Box<dyn Encode>),
+        any::<usize>().prop_map(|depth| Box::new(QueueDepth { depth }) as Box<dyn Encode>),
+        any::<u16>().prop_map(|id| Box::new(FollowAck {
+            client_id: ClientId(id)
+        }) as Box<dyn Encode>),
+        (any::<u16>(), product()).prop_map(|(id, product)| Box::new(FollowedTrade {
+            client_id: ClientId(id),
+            product,
+        }) as Box<dyn Encode>),
+        (duration(), any::<usize>()).prop_map(|(uptime, clients)| Box::new(Info {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime,
+            clients,
+        }) as Box<dyn Encode>),
+        (reject_reason(), prop::option::of(duration())).prop_map(|(reason, retry_after)| {
+            Box::new(Nack {
+                reason,
+                retry_after,
+            }) as Box<dyn Encode>
+        }),
+        (product(), any::<u32>(), any::<u32>()).prop_map(|(product, buys, sells)| Box::new(
+            Snapshot {
+                product,
+                buys,
+                sells,
+            }
+        )
+            as Box<dyn Encode>),
+        trade().prop_map(|trade| Box::new(trade) as Box<dyn Encode>),
+        (product(), any::<u64>(), side(), duration(), venue()).prop_map(
+            |(product, trade_id, side, timestamp, venue)| Box::new(Execution {
+                product,
+                trade_id,
+                side,
+                timestamp,
+                venue,
+            }) as Box<dyn Encode>
+        ),
+        prop::collection::vec(trade(), 0..32)
+            .prop_map(|trades| Box::new(Trades { trades }) as Box<dyn Encode>),
+        (product(), any::<f64>())
+            .prop_map(|(product, value)| Box::new(Imbalance { product, value }) as Box<dyn Encode>),
+        order().prop_map(|order| Box::new(order) as Box<dyn Encode>),
+        (side(), product())
+            .prop_map(|(side, product)| Box::new(Echo { side, product }) as Box<dyn Encode>),
+        (
+            product(),
+            prop::collection::vec((any::<u64>(), side()), 0..8)
+        )
+            .prop_map(|(product, trades)| Box::new(Tape {
+                product,
+                trades: trades
+                    .into_iter()
+                    .map(|(trade_id, side)| TapeEntry { trade_id, side })
+                    .collect(),
+            }) as Box<dyn Encode>),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn test_parsing_arbitrary_lines_does_not_panic(line in any::<String>()) {
+        let _ = line.parse::<Request>();
+        let _ = line.parse::<Order>();
+        let _ = line.parse::<ClientOption>();
+    }
+
+    #[test]
+    fn test_parsing_order_like_lines_does_not_panic(
+        line in "(BUY|SELL|INFO|OPTS|SUB|UNSUB|FOLLOW)?:?[A-Z0-9_]{0,20}(@[a-z]{0,5}=?[^@\n]{0,80})*",
+    ) {
+        let _ = line.parse::<Request>();
+    }
+
+    #[test]
+    fn test_encoding_into_any_buffer_does_not_panic(frame in frame(), size in 0..256usize) {
+        let mut buffer = vec![0; size];
+        if let Ok(length) = frame.encode(&mut buffer) {
+            prop_assert!(length <= size);
+        }
+    }
+
+    #[test]
+    fn test_encoded_len_matches_encoding(frame in frame()) {
+        // One byte to spare, so a frame longer than announced is noticed
+        let mut buffer = vec![0; frame.encoded_len() + 1];
+        let length = frame.encode(&mut buffer).unwrap();
+        prop_assert_eq!(length, frame.encoded_len());
+    }
+
+    #[test]
+    fn test_order_round_trips_through_text(order in order()) {
+        let mut buffer = [0; 1024];
+        let length = order.encode(&mut buffer).unwrap();
+        let line = std::str::from_utf8(&buffer[..length]).unwrap();
+        let line = line.strip_suffix('\n').unwrap();
+
+        let Request::Order(decoded) = line.parse::<Request>().unwrap() else {
+            panic!("{line:?} did not decode as an order");
+        };
+        prop_assert_eq!(decoded.side, order.side);
+        prop_assert_eq!(decoded.product, order.product);
+        prop_assert_eq!(decoded.client_order_id, order.client_order_id);
+        prop_assert_eq!(decoded.venue, order.venue);
+    }
+}
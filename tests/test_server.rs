@@ -1,14 +1,29 @@
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
 use single_thread_async_server::{
-    decoder::{Decoder, DecoderEvent, DecoderTaskControl},
-    encoder::{Encoder, EncoderTaskControl},
-    server::Server,
+    clock::MockClock,
+    codec,
+    config::Config,
+    decoder::{Decoder, DecoderConfig, DecoderEvent, DecoderTaskControl},
+    encoder::{Encoder, EncoderConfig, EncoderTaskControl},
+    matcher::{CountEngine, Match, Matcher, MatchingEngine},
+    models::{ClientId, Execution, Order, Product, Side},
+    products::UnknownProductPolicy,
+    server::{
+        AdminCommand, ClientIdAllocation, OrderHandling, Server, ServerConfig, ServerError,
+        StartupOrderHandling,
+    },
+    sink::TradeSink,
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
+    sync::mpsc::Sender,
     task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
@@ -46,10 +61,57 @@ impl TcpClient {
         line
     }
 
-    async fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+    async fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
         self.writer.write_all(line.as_bytes()).await?;
         self.writer.write_all(b"\n").await?;
 
+        Ok(())
+    }
+
+    /// Sends an order like `BUY:APPLE` and waits for its ack.
+    async fn send_order(&mut self, order: &str) -> anyhow::Result<()> {
+        let (_, product) = order.split_once(':').context("Order without a product")?;
+        self.send_line(order).await?;
+
+        self.expect_line(&format!("ACK:{product}")).await
+    }
+
+    async fn expect_line(&mut self, expected: &str) -> anyhow::Result<()> {
+        match self.read_line().await? {
+            Some(line) => {
+                anyhow::ensure!(line == expected, "Expected {expected}, got: {line}");
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Expected a line")),
+        }
+    }
+
+    /// Reads exactly `len` bytes, newlines included, for checking the framing itself.
+    async fn read_exact_frame(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut frame = vec![0; len];
+        self.line_reader.get_mut().read_exact(&mut frame).await?;
+
+        tracing::debug!("Received bytes: {:?}", frame.escape_ascii().to_string());
+
+        Ok(frame)
+    }
+
+    /// Reads as many bytes as `expected` holds and compares them byte for byte.
+    async fn assert_bytes(&mut self, expected: &[u8]) -> anyhow::Result<()> {
+        let frame = self.read_exact_frame(expected.len()).await?;
+        anyhow::ensure!(
+            frame == expected,
+            "Expected {}, got: {}",
+            expected.escape_ascii(),
+            frame.escape_ascii()
+        );
+
+        Ok(())
+    }
+
+    async fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.send_line(line).await?;
+
         // NOTE: .next_line() wipes the newline
         const EXPECTED_ACK: &str = "ACK:MESSAGE";
         let line = self.read_line().await?;
@@ -68,6 +130,38 @@ impl TcpClient {
         }
     }
 
+    async fn subscribe(&mut self, product: &str) -> anyhow::Result<()> {
+        self.send_line(&format!("SUB:{product}")).await?;
+        self.expect_line(&format!("ACK:SUB:{product}")).await?;
+        if product != "*" {
+            let line = self.read_line().await?.context("Expected a snapshot")?;
+            anyhow::ensure!(
+                line.starts_with(&format!("SNAPSHOT:{product}:")),
+                "Expected a snapshot, got: {line}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Sends a PROXY protocol v1 header for a client connecting from `port`.
+    async fn send_proxy_header(&mut self, port: u16) -> anyhow::Result<()> {
+        let header = format!("PROXY TCP4 203.0.113.7 127.0.0.1 {port} 8888\r\n");
+        self.writer.write_all(header.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    async fn login(&mut self) -> anyhow::Result<ClientId> {
+        let line = self.read_line().await?.context("Expected a line")?;
+        let client_id = line
+            .strip_prefix("LOGIN:")
+            .with_context(|| format!("Expected a login, got: {line}"))?
+            .parse()?;
+
+        Ok(ClientId(client_id))
+    }
+
     async fn verify_login(&mut self) -> anyhow::Result<()> {
         let line = self.read_line().await?;
         match line {
@@ -84,10 +178,37 @@ struct TestServerHandle {
     pub server: Server,
     pub encoder: Encoder,
     pub decoder: Decoder,
+    /// Address the server is listening on, which may be past the requested port if it was taken.
+    pub address: String,
+    /// Replayed through `Server::run_with_source` before the server starts accepting.
+    pub source: Option<&'static [u8]>,
 }
 
+/// Ports tried by `create_server_with_config`, starting at the requested one, before giving up.
+const BIND_ATTEMPTS: u16 = 8;
+
 async fn create_server(port: u16) -> anyhow::Result<TestServerHandle> {
-    let server = Server::bind(("0.0.0.0", port)).await?;
+    create_server_with_config(port, ServerConfig::default()).await
+}
+
+async fn create_server_with_config(
+    port: u16,
+    config: ServerConfig,
+) -> anyhow::Result<TestServerHandle> {
+    let mut port = port;
+    let mut attempts = 1;
+    let server = loop {
+        match Server::bind_with_config(("0.0.0.0", port), config.clone()).await {
+            Err(e)
+                if attempts < BIND_ATTEMPTS
+                    && e.downcast_ref::<ServerError>() == Some(&ServerError::AddressInUse) =>
+            {
+                port += 1;
+                attempts += 1;
+            }
+            result => break result?,
+        }
+    };
     let encoder = Encoder::default();
     let decoder = Decoder::default();
 
@@ -95,12 +216,28 @@ async fn create_server(port: u16) -> anyhow::Result<TestServerHandle> {
         server,
         encoder,
         decoder,
+        address: format!("0.0.0.0:{port}"),
+        source: None,
     })
 }
 
 async fn run_all(
     handle: TestServerHandle,
 ) -> anyhow::Result<(Vec<JoinHandle<anyhow::Result<()>>>, CancellationToken)> {
+    let (futures, cancellation_token, _) = run_all_with_event_sender(handle).await?;
+
+    Ok((futures, cancellation_token))
+}
+
+/// Like `run_all`, but also hands out a sender to inject decoder events directly into the
+/// server. The server only finishes draining once that sender is dropped.
+async fn run_all_with_event_sender(
+    handle: TestServerHandle,
+) -> anyhow::Result<(
+    Vec<JoinHandle<anyhow::Result<()>>>,
+    CancellationToken,
+    Sender<DecoderEvent>,
+)> {
     let mut futures = Vec::new();
     let (encoder_sender, encoder_receiver) =
         tokio::sync::mpsc::channel::<EncoderTaskControl>(u8::MAX as usize);
@@ -111,18 +248,29 @@ async fn run_all(
 
     let cancellation_token = CancellationToken::new();
     let cloned_cancellation_token = cancellation_token.clone();
+    let encoder_flush_token = CancellationToken::new();
+    let cloned_encoder_flush_token = encoder_flush_token.clone();
 
     let mut encoder = handle.encoder;
     let mut decoder = handle.decoder;
-    let server = handle.server;
+    let mut server = handle.server;
 
+    let injected_event_sender = decoder_event_sender.clone();
     let encoder_fut =
-        tokio::spawn(async move { encoder.run(encoder_receiver, decoder_event_receiver).await });
+        tokio::spawn(async move { encoder.run(encoder_receiver, encoder_flush_token).await });
     let decoder_fut =
         tokio::spawn(async move { decoder.run(decoder_receiver, decoder_event_sender).await });
+    let source = handle.source.unwrap_or_default();
     let server_fut = tokio::spawn(async move {
         server
-            .run(encoder_sender, decoder_sender, cloned_cancellation_token)
+            .run_with_source(
+                source,
+                encoder_sender,
+                decoder_sender,
+                decoder_event_receiver,
+                cloned_cancellation_token,
+                cloned_encoder_flush_token,
+            )
             .await
     });
 
@@ -130,7 +278,7 @@ async fn run_all(
     futures.push(decoder_fut);
     futures.push(server_fut);
 
-    Ok((futures, cancellation_token))
+    Ok((futures, cancellation_token, injected_event_sender))
 }
 
 async fn stop_all(
@@ -140,7 +288,7 @@ async fn stop_all(
     cancellation_token.cancel();
 
     // We don't care if they exit gracefully or not in tests
-    let _ = futures::future::join_all(futures.into_iter()).await;
+    let _ = futures::future::join_all(futures).await;
 
     Ok(())
 }
@@ -157,9 +305,10 @@ fn regex_matches(line: &str, regex: &str) -> anyhow::Result<()> {
 #[tokio::test]
 async fn test_login() {
     let handle = create_server(9000).await.expect("Failed to create server");
+    let address = handle.address.clone();
     let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
 
-    let mut client1 = TcpClient::connect("0.0.0.0:9000").await;
+    let mut client1 = TcpClient::connect(&address).await;
 
     client1
         .verify_login()
@@ -176,21 +325,22 @@ const HELLO_WORLD_MESSAGE_RECEIVED: &str = r"MESSAGE:\d+ Hello, World!";
 #[tokio::test]
 async fn test_messaging() {
     let handle = create_server(9001).await.expect("Failed to create server");
+    let address = handle.address.clone();
     let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
 
-    let mut client1 = TcpClient::connect("0.0.0.0:9001").await;
+    let mut client1 = TcpClient::connect(&address).await;
     client1
         .verify_login()
         .await
         .expect("Failed to verify login");
 
-    let mut client2 = TcpClient::connect("0.0.0.0:9001").await;
+    let mut client2 = TcpClient::connect(&address).await;
     client2
         .verify_login()
         .await
         .expect("Failed to verify login");
 
-    let mut client3 = TcpClient::connect("0.0.0.0:9001").await;
+    let mut client3 = TcpClient::connect(&address).await;
     client3
         .verify_login()
         .await
@@ -226,3 +376,2290 @@ async fn test_messaging() {
         .await
         .expect("Failed to stop server");
 }
+
+#[tokio::test]
+async fn test_max_accept_rate() {
+    const RATE: u32 = 5;
+    const CONNECTIONS: usize = 30;
+
+    let config = ServerConfig {
+        max_accept_rate: Some(RATE),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9002, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut clients = Vec::with_capacity(CONNECTIONS);
+    for _ in 0..CONNECTIONS {
+        clients.push(TcpClient::connect(&address).await);
+    }
+
+    // Connections over the rate are closed without ever receiving a LOGIN frame
+    let mut logged_in = 0;
+    for client in &mut clients {
+        if client.verify_login().await.is_ok() {
+            logged_in += 1;
+        }
+    }
+
+    assert!(
+        (RATE..=RATE + 2).contains(&logged_in),
+        "Expected roughly {RATE} accepted connections, got {logged_in}",
+    );
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_duplicate_client_order_id() {
+    let handle = create_server(9003).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    client
+        .send_line("BUY:APPLE@clid=abc123")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE@clid=abc123")
+        .await
+        .expect("Failed to receive ack");
+
+    client
+        .send_line("BUY:APPLE@clid=abc123")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("NACK:DUPLICATE")
+        .await
+        .expect("Failed to receive nack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_trade_at_cancellation_is_delivered() {
+    let handle = create_server(9004).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token, event_sender) = run_all_with_event_sender(handle)
+        .await
+        .expect("Failed to run server");
+
+    let mut seller = TcpClient::connect(&address).await;
+    seller.verify_login().await.expect("Failed to verify login");
+    let mut buyer = TcpClient::connect(&address).await;
+    let buyer_id = buyer.login().await.expect("Failed to login");
+    for client in [&mut seller, &mut buyer] {
+        client
+            .subscribe("APPLE")
+            .await
+            .expect("Failed to subscribe");
+    }
+
+    seller
+        .send_line("SELL:APPLE")
+        .await
+        .expect("Failed to send order");
+    seller
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    // The crossing order is still in flight when the server is cancelled
+    event_sender
+        .send(DecoderEvent::Order(
+            buyer_id,
+            "BUY:APPLE".parse().expect("Failed to parse order"),
+            None,
+            tokio::time::Instant::now(),
+        ))
+        .await
+        .expect("Failed to inject order");
+    drop(event_sender);
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+
+    buyer
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+    buyer
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+    seller
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+}
+
+#[tokio::test]
+async fn test_batched_acks_use_fewer_writes() {
+    const ORDERS: u64 = 5;
+
+    let mut handle = create_server(9005).await.expect("Failed to create server");
+    handle.encoder = Encoder::new(EncoderConfig {
+        flush_interval: Some(Duration::from_millis(50)),
+        ..EncoderConfig::default()
+    });
+    let stats = handle.encoder.stats();
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    let writes_after_login = stats.writes();
+
+    let orders = vec!["BUY:APPLE"; ORDERS as usize].join("\n");
+    client
+        .send_line(&orders)
+        .await
+        .expect("Failed to send orders");
+    for _ in 0..ORDERS {
+        client
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    let ack_writes = stats.writes() - writes_after_login;
+    assert!(
+        ack_writes < ORDERS,
+        "Expected fewer than {ORDERS} writes, got {ack_writes}"
+    );
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+async fn create_server_with_product_policy(
+    port: u16,
+    unknown_product_policy: UnknownProductPolicy,
+) -> anyhow::Result<TestServerHandle> {
+    let config = ServerConfig {
+        unknown_product_policy,
+        ..ServerConfig::default()
+    };
+
+    create_server_with_config(port, config).await
+}
+
+#[tokio::test]
+async fn test_unknown_product_rejected_with_nack() {
+    let handle = create_server_with_product_policy(9006, UnknownProductPolicy::RejectWithNack)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    client
+        .send_line("BUY:BANANA")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("NACK:UNKNOWN_PRODUCT")
+        .await
+        .expect("Failed to receive nack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_unknown_product_silently_dropped() {
+    let handle = create_server_with_product_policy(9007, UnknownProductPolicy::SilentlyDrop)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    client
+        .send_line("BUY:BANANA")
+        .await
+        .expect("Failed to send order");
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    // Nothing is sent for the unknown product
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_unknown_product_auto_registered() {
+    let handle = create_server_with_product_policy(9008, UnknownProductPolicy::AutoRegister)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut buyer = TcpClient::connect(&address).await;
+    buyer.verify_login().await.expect("Failed to verify login");
+    let mut seller = TcpClient::connect(&address).await;
+    seller.verify_login().await.expect("Failed to verify login");
+    for client in [&mut buyer, &mut seller] {
+        client
+            .subscribe("BANANA")
+            .await
+            .expect("Failed to subscribe");
+    }
+
+    buyer
+        .send_line("BUY:BANANA")
+        .await
+        .expect("Failed to send order");
+    buyer
+        .expect_line("ACK:BANANA")
+        .await
+        .expect("Failed to receive ack");
+
+    seller
+        .send_line("SELL:BANANA")
+        .await
+        .expect("Failed to send order");
+    seller
+        .expect_line("ACK:BANANA")
+        .await
+        .expect("Failed to receive ack");
+    seller
+        .expect_line("TRADE:BANANA")
+        .await
+        .expect("Failed to receive trade");
+    buyer
+        .expect_line("TRADE:BANANA")
+        .await
+        .expect("Failed to receive trade");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_info() {
+    let handle = create_server(9009).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    client.send_line("INFO").await.expect("Failed to send info");
+    let line = client
+        .read_line()
+        .await
+        .expect("Failed to read info")
+        .expect("Expected a line");
+
+    let re = regex::Regex::new(r"^INFO:version=(.+),uptime=(\d+),clients=(\d+)$")
+        .expect("Failed to compile regex");
+    let captures = re.captures(&line).expect("Info did not match");
+    assert_eq!(&captures[1], env!("CARGO_PKG_VERSION"));
+    let uptime: u64 = captures[2].parse().expect("Failed to parse uptime");
+    assert!(uptime < 60, "Implausible uptime: {uptime}");
+    assert_eq!(&captures[3], "1");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_accept_paused_while_encoder_backed_up() {
+    const CHANNEL_SIZE: usize = 16;
+
+    let config = ServerConfig {
+        min_encoder_capacity: Some(4),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9010, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+
+    let (encoder_sender, encoder_receiver) =
+        tokio::sync::mpsc::channel::<EncoderTaskControl>(CHANNEL_SIZE);
+    let (decoder_sender, decoder_receiver) =
+        tokio::sync::mpsc::channel::<DecoderTaskControl>(CHANNEL_SIZE);
+    let (decoder_event_sender, decoder_event_receiver) =
+        tokio::sync::mpsc::channel::<DecoderEvent>(CHANNEL_SIZE);
+    let cancellation_token = CancellationToken::new();
+    let encoder_flush_token = CancellationToken::new();
+
+    // Back up the encoder channel before anything consumes it
+    for _ in 0..CHANNEL_SIZE - 1 {
+        encoder_sender
+            .send(EncoderTaskControl::ClientDisconnected(ClientId(0)))
+            .await
+            .expect("Failed to fill encoder channel");
+    }
+
+    let mut encoder = handle.encoder;
+    let mut decoder = handle.decoder;
+    let mut server = handle.server;
+    let decoder_fut =
+        tokio::spawn(async move { decoder.run(decoder_receiver, decoder_event_sender).await });
+    let server_token = cancellation_token.clone();
+    let server_flush_token = encoder_flush_token.clone();
+    let server_fut = tokio::spawn(async move {
+        server
+            .run(
+                encoder_sender,
+                decoder_sender,
+                decoder_event_receiver,
+                server_token,
+                server_flush_token,
+            )
+            .await
+    });
+
+    let mut client = TcpClient::connect(&address).await;
+    let login = tokio::time::timeout(Duration::from_millis(200), client.verify_login()).await;
+    assert!(
+        login.is_err(),
+        "Connection accepted while encoder was backed up"
+    );
+
+    // Draining the encoder resumes accepting, and the pending connection logs in
+    let encoder_fut =
+        tokio::spawn(async move { encoder.run(encoder_receiver, encoder_flush_token).await });
+    client.verify_login().await.expect("Failed to verify login");
+
+    stop_all(
+        vec![encoder_fut, decoder_fut, server_fut],
+        cancellation_token,
+    )
+    .await
+    .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_mock_clock_expires_client_order_ids() {
+    let clock = MockClock::new();
+    let config = ServerConfig {
+        client_order_id_window: Duration::from_secs(60),
+        clock: Arc::new(clock.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9011, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    for expected in ["ACK:APPLE@clid=a", "NACK:DUPLICATE"] {
+        client
+            .send_line("BUY:APPLE@clid=a")
+            .await
+            .expect("Failed to send order");
+        client
+            .expect_line(expected)
+            .await
+            .expect("Failed to receive response");
+    }
+
+    // No waiting a minute of real time for the window to pass
+    clock.advance(Duration::from_secs(61));
+    client
+        .send_line("BUY:APPLE@clid=a")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE@clid=a")
+        .await
+        .expect("Failed to receive ack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let mut handle = create_server(9012).await.expect("Failed to create server");
+    handle.decoder = Decoder::new(DecoderConfig {
+        handshake_timeout: Some(Duration::from_millis(100)),
+        ..DecoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    // Send nothing and get dropped once the window has passed
+    client
+        .expect_line("NACK:HANDSHAKE_TIMEOUT")
+        .await
+        .expect("Failed to receive nack");
+    let line = client.read_line().await.expect("Failed to read");
+    assert_eq!(line, None, "Expected the connection to be closed");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_ping_before_handshake() {
+    let mut handle = create_server(9036).await.expect("Failed to create server");
+    handle.decoder = Decoder::new(DecoderConfig {
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..DecoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut probe = TcpClient::connect(&address).await;
+    probe.verify_login().await.expect("Failed to verify login");
+    for _ in 0..2 {
+        probe.send_line("PING").await.expect("Failed to send ping");
+        probe
+            .expect_line("PONG")
+            .await
+            .expect("Failed to receive pong");
+    }
+
+    // Probes do not complete the handshake
+    probe
+        .expect_line("NACK:HANDSHAKE_TIMEOUT")
+        .await
+        .expect("Failed to receive nack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_idle_warning_before_idle_timeout() {
+    let mut handle = create_server(9027).await.expect("Failed to create server");
+    handle.decoder = Decoder::new(DecoderConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        idle_warning: Some(Duration::from_millis(200)),
+        ..DecoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .expect_line("WARN:IDLE")
+        .await
+        .expect("Failed to receive idle warning");
+
+    // Answering the warning restarts the idle timer, so the next frame is a second warning
+    client.send_line("INFO").await.expect("Failed to send info");
+    let line = client
+        .read_line()
+        .await
+        .expect("Failed to read line")
+        .expect("Expected a line");
+    assert!(line.starts_with("INFO:"), "Expected info, got: {line}");
+    client
+        .expect_line("WARN:IDLE")
+        .await
+        .expect("Failed to receive idle warning");
+
+    client
+        .expect_line("NACK:IDLE_TIMEOUT")
+        .await
+        .expect("Failed to receive nack");
+    let line = client.read_line().await.expect("Failed to read");
+    assert_eq!(line, None, "Expected the connection to be closed");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_spamming_client_does_not_starve_others() {
+    let mut handle = create_server(9028).await.expect("Failed to create server");
+    handle.decoder = Decoder::new(DecoderConfig {
+        max_pending_orders: NonZeroUsize::new(4),
+        ..DecoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut spammer = TcpClient::connect(&address).await;
+    spammer
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    let mut other = TcpClient::connect(&address).await;
+    other.verify_login().await.expect("Failed to verify login");
+
+    let orders = "BUY:APPLE\n".repeat(20_000);
+    let spam = tokio::spawn(async move {
+        spammer
+            .writer
+            .write_all(orders.as_bytes())
+            .await
+            .expect("Failed to spam orders");
+        while let Ok(Some(_)) = spammer.read_line().await {}
+    });
+
+    for _ in 0..10 {
+        other
+            .send_line("BUY:PEAR")
+            .await
+            .expect("Failed to send order");
+        tokio::time::timeout(Duration::from_millis(500), other.expect_line("ACK:PEAR"))
+            .await
+            .expect("Order was starved by the spammer")
+            .expect("Failed to receive ack");
+    }
+
+    spam.abort();
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_ack_for_disconnected_client_is_dropped() {
+    let handle = create_server(9013).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token, event_sender) = run_all_with_event_sender(handle)
+        .await
+        .expect("Failed to run server");
+
+    // Disconnecting right after submitting an order
+    let mut client = TcpClient::connect(&address).await;
+    let client_id = client.login().await.expect("Failed to login");
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    drop(client);
+
+    // Force the ack to be routed after the disconnect was handled
+    let order: Order = "BUY:PEAR".parse().expect("Failed to parse order");
+    event_sender
+        .send(DecoderEvent::ClientDisconnected(client_id))
+        .await
+        .expect("Failed to inject disconnect");
+    event_sender
+        .send(DecoderEvent::Order(
+            client_id,
+            order,
+            None,
+            tokio::time::Instant::now(),
+        ))
+        .await
+        .expect("Failed to inject order");
+
+    let mut other = TcpClient::connect(&address).await;
+    other.verify_login().await.expect("Failed to verify login");
+    other
+        .send_line("BUY:TOMATO")
+        .await
+        .expect("Failed to send order");
+    other
+        .expect_line("ACK:TOMATO")
+        .await
+        .expect("Encoder stopped after a missing client");
+    assert!(!futures[0].is_finished(), "Encoder task exited");
+
+    drop(event_sender);
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_imbalance_signal() {
+    let config = ServerConfig {
+        imbalance_thresholds: vec![-0.5, 0.5],
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9014, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("APPLE")
+        .await
+        .expect("Failed to subscribe");
+
+    // Only buys resting, past the upper threshold
+    trader
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    trader
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+    subscriber
+        .expect_line("IMBAL:APPLE:1.00")
+        .await
+        .expect("Failed to receive imbalance");
+
+    // Still past it, nothing is published
+    trader
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    trader
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    // Back to balanced once the buys trade away
+    for expected in ["TRADE:APPLE", "TRADE:APPLE", "IMBAL:APPLE:0.00"] {
+        if expected.starts_with("TRADE") {
+            trader
+                .send_line("SELL:APPLE")
+                .await
+                .expect("Failed to send order");
+        }
+        subscriber
+            .expect_line(expected)
+            .await
+            .expect("Failed to receive market data");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_login_precedes_order_ack() {
+    let handle = create_server(9015).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    for _ in 0..20 {
+        // The order is on the wire before the server has even accepted the connection
+        let mut client = TcpClient::connect(&address).await;
+        client
+            .send_line("BUY:APPLE")
+            .await
+            .expect("Failed to send order");
+        client.verify_login().await.expect("Failed to verify login");
+        client
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_market_data_only_reaches_subscribers() {
+    let handle = create_server(9016).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("APPLE")
+        .await
+        .expect("Failed to subscribe");
+
+    // The trader is not subscribed, so only acks come back to it
+    for order in ["BUY:PEAR", "SELL:PEAR", "BUY:APPLE", "SELL:APPLE"] {
+        trader.send_line(order).await.expect("Failed to send order");
+        let product = order.split(':').nth(1).expect("Order without product");
+        trader
+            .expect_line(&format!("ACK:{product}"))
+            .await
+            .expect("Failed to receive ack");
+    }
+    subscriber
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+
+    subscriber
+        .send_line("UNSUB:APPLE")
+        .await
+        .expect("Failed to unsubscribe");
+    subscriber
+        .expect_line("ACK:UNSUB:APPLE")
+        .await
+        .expect("Failed to receive unsubscribe ack");
+    for order in ["BUY:APPLE", "SELL:APPLE"] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    // Nothing was queued for the subscriber ahead of the info response
+    subscriber
+        .send_line("INFO")
+        .await
+        .expect("Failed to send info");
+    let line = subscriber
+        .read_line()
+        .await
+        .expect("Failed to read line")
+        .expect("Expected a line");
+    assert!(line.starts_with("INFO:"), "Expected info, got: {line}");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_wildcard_subscription_covers_every_product() {
+    let handle = create_server_with_product_policy(9026, UnknownProductPolicy::AutoRegister)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("*")
+        .await
+        .expect("Failed to subscribe");
+
+    // BANANA is only registered by the first order for it, after the subscription
+    for product in ["APPLE", "PEAR", "BANANA"] {
+        for side in ["BUY", "SELL"] {
+            trader
+                .send_line(&format!("{side}:{product}"))
+                .await
+                .expect("Failed to send order");
+            trader
+                .expect_line(&format!("ACK:{product}"))
+                .await
+                .expect("Failed to receive ack");
+        }
+        subscriber
+            .expect_line(&format!("TRADE:{product}"))
+            .await
+            .expect("Failed to receive trade");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_subscribe_starts_with_book_snapshot() {
+    let handle = create_server(9035).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    // Two buys rest in the book, the sell matches one of them
+    for side in ["BUY", "BUY", "BUY", "SELL"] {
+        trader
+            .send_line(&format!("{side}:APPLE"))
+            .await
+            .expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    for (product, snapshot) in [
+        ("APPLE", "SNAPSHOT:APPLE:buys=2,sells=0"),
+        ("PEAR", "SNAPSHOT:PEAR:buys=0,sells=0"),
+    ] {
+        subscriber
+            .send_line(&format!("SUB:{product}"))
+            .await
+            .expect("Failed to subscribe");
+        subscriber
+            .expect_line(&format!("ACK:SUB:{product}"))
+            .await
+            .expect("Failed to receive subscription ack");
+        subscriber
+            .expect_line(snapshot)
+            .await
+            .expect("Failed to receive snapshot");
+    }
+
+    // Updates follow the snapshot
+    trader
+        .send_line("SELL:APPLE")
+        .await
+        .expect("Failed to send order");
+    subscriber
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_server_from_config() {
+    let config: Config = r#"
+        bind = "0.0.0.0:9044"
+        products = ["APPLE"]
+        max_connections = 1
+    "#
+    .parse()
+    .expect("Failed to parse config");
+    let handle = TestServerHandle {
+        server: Server::from_config(&config)
+            .await
+            .expect("Failed to create server"),
+        encoder: Encoder::new(config.encoder_config()),
+        decoder: Decoder::new(config.decoder_config()),
+        address: config.bind.clone(),
+        source: None,
+    };
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut first = TcpClient::connect("0.0.0.0:9044").await;
+    first.verify_login().await.expect("Failed to verify login");
+    let mut second = TcpClient::connect("0.0.0.0:9044").await;
+    second
+        .expect_line("NACK:OVERLOADED retry_after=5")
+        .await
+        .expect("Connection over the limit was not rejected");
+
+    // Orders for products left out of the config are dropped
+    for order in ["BUY:PEAR", "BUY:APPLE"] {
+        first.send_line(order).await.expect("Failed to send order");
+    }
+    first
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[cfg(feature = "dashboard")]
+#[tokio::test]
+async fn test_dashboard_receives_trades_as_json() {
+    use futures::StreamExt;
+    use single_thread_async_server::dashboard;
+
+    let (events, _) = tokio::sync::broadcast::channel(16);
+    let mut handle = create_server(9045).await.expect("Failed to create server");
+    handle.server = handle.server.with_events(events.clone());
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:9046")
+        .await
+        .expect("Failed to bind dashboard");
+    tokio::spawn(dashboard::serve(
+        listener,
+        events,
+        cancellation_token.clone(),
+    ));
+
+    let (mut websocket, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:9046")
+        .await
+        .expect("Failed to connect dashboard");
+    let mut trader = TcpClient::connect(&address).await;
+    let client_id = trader.login().await.expect("Failed to log in");
+    for order in ["BUY:APPLE", "SELL:APPLE"] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    let mut next_event = async || -> serde_json::Value {
+        let message = websocket
+            .next()
+            .await
+            .expect("Dashboard closed")
+            .expect("Failed to read event");
+        serde_json::from_str(message.to_text().expect("Event is not text")).expect("Not JSON")
+    };
+    assert_eq!(
+        next_event().await,
+        serde_json::json!({ "event": "connected", "client_id": client_id.0 })
+    );
+    let trade = next_event().await;
+    assert_eq!(trade["event"], "trade");
+    assert_eq!(trade["product"], "APPLE");
+    assert_eq!(trade["side"], "SELL");
+    assert_eq!(trade["trade_id"], 1);
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+/// Matches like the default engine, but takes a while about it.
+#[derive(Debug, Default)]
+struct SlowEngine(CountEngine);
+
+impl MatchingEngine for SlowEngine {
+    fn add_order(&mut self, order: &Order) -> Option<Match> {
+        std::thread::sleep(Duration::from_millis(50));
+        self.0.add_order(order)
+    }
+
+    fn seed(&mut self, side: Side) {
+        self.0.seed(side);
+    }
+
+    fn clear(&mut self) -> u32 {
+        self.0.clear()
+    }
+}
+
+#[tokio::test]
+async fn test_slow_acks_are_counted() {
+    let mut handle = create_server(9047).await.expect("Failed to create server");
+    handle.server = handle
+        .server
+        .with_matcher(Matcher::new().with_engine(Product::Apples, Box::new(SlowEngine::default())));
+    handle.encoder = Encoder::new(EncoderConfig {
+        ack_latency_target: Some(Duration::from_millis(10)),
+        ..EncoderConfig::default()
+    });
+    let stats = handle.encoder.stats();
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .send_line("BUY:PEAR")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:PEAR")
+        .await
+        .expect("Failed to receive ack");
+    assert_eq!(stats.slow_acks(), 0);
+
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+    assert_eq!(stats.slow_acks(), 1);
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_max_connections() {
+    let config = ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9017, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut first = TcpClient::connect(&address).await;
+    first.verify_login().await.expect("Failed to verify login");
+    let mut second = TcpClient::connect(&address).await;
+    second
+        .expect_line("NACK:OVERLOADED retry_after=5")
+        .await
+        .expect("Connection over the limit was not rejected");
+
+    // The slot frees up once the first client leaves
+    drop(first);
+    let mut third = None;
+    for _ in 0..50 {
+        let mut client = TcpClient::connect(&address).await;
+        if client.verify_login().await.is_ok() {
+            third = Some(client);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(third.is_some(), "Connection limit was never released");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_binary_binds_configured_address() {
+    let _server = tokio::process::Command::new(env!("CARGO_BIN_EXE_single-thread-async-server"))
+        .args(["--bind", "127.0.0.1:9018", "--max-connections", "8"])
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to launch server");
+
+    let mut client = None;
+    for _ in 0..100 {
+        if let Ok(connected) = TcpClient::try_connect("127.0.0.1:9018").await {
+            client = Some(connected);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut client = client.expect("Server never started listening");
+    client.verify_login().await.expect("Failed to verify login");
+}
+
+#[tokio::test]
+async fn test_binary_emits_json_logs() {
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_single-thread-async-server"))
+        .args(["--bind", "127.0.0.1:9021", "--log-format", "json"])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to launch server");
+    let stdout = server.stdout.take().expect("Missing stdout");
+    let mut lines = BufReader::new(stdout).lines();
+
+    // Reading the startup logs, which end once the server is listening
+    let mut messages = Vec::new();
+    while let Some(line) = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+        .await
+        .expect("Timed out waiting for logs")
+        .expect("Failed to read logs")
+    {
+        let log: serde_json::Value = serde_json::from_str(&line).expect("Log line is not JSON");
+        assert!(log["level"].is_string(), "Missing level: {line}");
+        let message = log["fields"]["message"]
+            .as_str()
+            .expect("Missing message")
+            .to_string();
+        if message == "Server started" {
+            break;
+        }
+        messages.push(message);
+    }
+    assert!(messages.iter().any(|m| m.starts_with("Starting with")));
+}
+
+#[tokio::test]
+async fn test_rejected_connection_intent_is_logged() {
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_single-thread-async-server"))
+        .args(["--bind", "127.0.0.1:9029", "--max-connections", "1"])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to launch server");
+    let stdout = server.stdout.take().expect("Missing stdout");
+    let mut lines = BufReader::new(stdout).lines();
+    let mut wait_for_log = async |needle: &str| {
+        while let Some(line) = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .expect("Timed out waiting for logs")
+            .expect("Failed to read logs")
+        {
+            if line.contains(needle) {
+                return line;
+            }
+        }
+        panic!("Server exited before logging {needle}");
+    };
+    wait_for_log("Server started").await;
+
+    let mut first = TcpClient::connect("127.0.0.1:9029").await;
+    first.verify_login().await.expect("Failed to verify login");
+    let mut second = TcpClient::connect("127.0.0.1:9029").await;
+    second
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    second
+        .expect_line("NACK:OVERLOADED retry_after=5")
+        .await
+        .expect("Connection over the limit was not rejected");
+
+    let log = wait_for_log("wanted").await;
+    assert!(log.contains("BUY:APPLE"), "Intent missing from: {log}");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_ctrl_c_shutdown_logs_no_errors() {
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_single-thread-async-server"))
+        .args(["--bind", "127.0.0.1:9030", "--log-format", "json"])
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to launch server");
+    let stdout = server.stdout.take().expect("Missing stdout");
+    let mut lines = BufReader::new(stdout).lines();
+    let mut next_log = async || {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .expect("Timed out waiting for logs")
+            .expect("Failed to read logs")?;
+        let log: serde_json::Value = serde_json::from_str(&line).expect("Log line is not JSON");
+        Some(log)
+    };
+    while let Some(log) = next_log().await {
+        if log["fields"]["message"] == "Server started" {
+            break;
+        }
+    }
+
+    let mut client = TcpClient::connect("127.0.0.1:9030").await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    let pid = server.id().expect("Server already exited").to_string();
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &pid])
+        .status()
+        .expect("Failed to run kill");
+    assert!(status.success());
+
+    let mut finished = Vec::new();
+    while let Some(log) = next_log().await {
+        assert_ne!(log["level"], "ERROR", "Error during shutdown: {log}");
+        if let Some(message) = log["fields"]["message"].as_str() {
+            if message.ends_with("finished gracefully") {
+                finished.push(message.to_string());
+            }
+        }
+    }
+    finished.sort();
+    assert_eq!(
+        finished,
+        [
+            "Decoder finished gracefully",
+            "Encoder finished gracefully",
+            "Server finished gracefully",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_tape_returns_most_recent_trades() {
+    let handle = create_server(9022).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    // Three trades, completed by a sell, a buy and a sell
+    for order in [
+        "BUY:APPLE",
+        "SELL:APPLE",
+        "SELL:APPLE",
+        "BUY:APPLE",
+        "BUY:APPLE",
+        "SELL:APPLE",
+    ] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    trader
+        .send_line("TAPE:APPLE:2")
+        .await
+        .expect("Failed to request tape");
+    for expected in [
+        "TAPE:APPLE:id=2,side=BUY",
+        "TAPE:APPLE:id=3,side=SELL",
+        "END",
+    ] {
+        trader
+            .expect_line(expected)
+            .await
+            .expect("Failed to receive tape");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_drain_refuses_new_clients_and_disconnects_the_rest() {
+    let (admin_sender, admin_receiver) = tokio::sync::mpsc::channel(1);
+    let mut handle = create_server(9023).await.expect("Failed to create server");
+    handle.server = handle.server.with_admin(admin_receiver);
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    let deadline = Duration::from_millis(300);
+    let drain_started = tokio::time::Instant::now();
+    admin_sender
+        .send(AdminCommand::Drain(deadline))
+        .await
+        .expect("Failed to send drain");
+
+    let mut refused = TcpClient::connect(&address).await;
+    refused
+        .expect_line("NACK:DRAINING")
+        .await
+        .expect("Failed to receive rejection");
+    assert_eq!(
+        refused.read_line().await.expect("Failed to read line"),
+        None
+    );
+    assert!(
+        drain_started.elapsed() < deadline,
+        "Not refused immediately"
+    );
+
+    // Still served until the deadline
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    client
+        .expect_line("BYE")
+        .await
+        .expect("Failed to receive bye");
+    assert!(drain_started.elapsed() >= deadline, "Disconnected early");
+    assert_eq!(client.read_line().await.expect("Failed to read line"), None);
+
+    // Ignored after the disconnect, neither traded nor able to take the server down
+    let _ = client.send_line("BUY:APPLE").await;
+    let _ = client.send_line("OPTS:nagle").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(
+        !futures.iter().any(JoinHandle::is_finished),
+        "Server exited"
+    );
+    assert!(!matches!(client.read_line().await, Ok(Some(_))));
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_paused_decoding_holds_orders_until_resumed() {
+    let (admin_sender, admin_receiver) = tokio::sync::mpsc::channel(1);
+    let mut handle = create_server(9031).await.expect("Failed to create server");
+    handle.server = handle.server.with_admin(admin_receiver);
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+
+    admin_sender
+        .send(AdminCommand::PauseDecoding)
+        .await
+        .expect("Failed to send pause");
+    // Gives the pause time to reach the decoder
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    let held = tokio::time::timeout(Duration::from_millis(200), client.read_line()).await;
+    assert!(held.is_err(), "Order handled while paused: {held:?}");
+
+    admin_sender
+        .send(AdminCommand::ResumeDecoding)
+        .await
+        .expect("Failed to send resume");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_replayed_source_matches_golden_tapes() {
+    let mut handle = create_server(9032).await.expect("Failed to create server");
+    handle.source = Some(include_bytes!("fixtures/replay_orders.txt"));
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    for product in ["APPLE", "PEAR"] {
+        client
+            .send_line(&format!("TAPE:{product}:10"))
+            .await
+            .expect("Failed to request tape");
+    }
+    for expected in include_str!("fixtures/replay_tapes.golden").lines() {
+        client
+            .expect_line(expected)
+            .await
+            .expect("Tape differs from the golden output");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_proxy_header_sets_the_peer_address() {
+    let config = ServerConfig {
+        proxy_protocol: true,
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9033, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    // Ids are source ports, so the login shows which address the server went with
+    let mut client = TcpClient::connect(&address).await;
+    client
+        .send_proxy_header(4242)
+        .await
+        .expect("Failed to send PROXY header");
+    assert_eq!(
+        client.login().await.expect("Failed to login"),
+        ClientId(4242)
+    );
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    let mut direct = TcpClient::connect(&address).await;
+    direct
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    assert_eq!(direct.read_line().await.expect("Failed to read"), None);
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_slow_setups_run_concurrently_up_to_the_limit() {
+    let config = ServerConfig {
+        proxy_protocol: true,
+        max_concurrent_setups: NonZeroUsize::new(2).unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9037, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    // Both take up a setup slot until they send their header
+    let mut first = TcpClient::connect(&address).await;
+    let mut second = TcpClient::connect(&address).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut third = TcpClient::connect(&address).await;
+    third
+        .send_proxy_header(3)
+        .await
+        .expect("Failed to send PROXY header");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), third.read_line())
+            .await
+            .is_err(),
+        "Expected the third connection to wait for a setup slot"
+    );
+
+    // Finishing one setup lets the waiting connection in, while the first is still slow
+    second
+        .send_proxy_header(2)
+        .await
+        .expect("Failed to send PROXY header");
+    assert_eq!(second.login().await.expect("Failed to login"), ClientId(2));
+    assert_eq!(third.login().await.expect("Failed to login"), ClientId(3));
+    first
+        .send_proxy_header(1)
+        .await
+        .expect("Failed to send PROXY header");
+    assert_eq!(first.login().await.expect("Failed to login"), ClientId(1));
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_rich_trades() {
+    let clock = MockClock::new();
+    let config = ServerConfig {
+        clock: Arc::new(clock.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9038, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut rich = TcpClient::connect(&address).await;
+    rich.verify_login().await.expect("Failed to verify login");
+    rich.send_line("OPTS:richtrades")
+        .await
+        .expect("Failed to set option");
+    rich.subscribe("APPLE").await.expect("Failed to subscribe");
+    let mut plain = TcpClient::connect(&address).await;
+    plain.verify_login().await.expect("Failed to verify login");
+    plain.subscribe("APPLE").await.expect("Failed to subscribe");
+
+    clock.advance(Duration::from_millis(1500));
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    for order in ["BUY:APPLE", "SELL:APPLE"] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    rich.expect_line("EXEC:product=APPLE,id=1,qty=1,side=SELL,ts=1500")
+        .await
+        .expect("Failed to receive execution");
+    plain
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+async fn create_starting_server(
+    port: u16,
+    startup_order_handling: StartupOrderHandling,
+) -> anyhow::Result<(TestServerHandle, MockClock)> {
+    let clock = MockClock::new();
+    let config = ServerConfig {
+        startup_grace_period: Some(Duration::from_secs(10)),
+        startup_order_handling,
+        clock: Arc::new(clock.clone()),
+        ..ServerConfig::default()
+    };
+
+    Ok((create_server_with_config(port, config).await?, clock))
+}
+
+#[tokio::test]
+async fn test_orders_rejected_during_startup() {
+    let (handle, clock) = create_starting_server(9040, StartupOrderHandling::Reject)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("NACK:STARTING")
+        .await
+        .expect("Failed to receive nack");
+
+    clock.advance(Duration::from_secs(10));
+    client
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_orders_queued_during_startup() {
+    let (handle, clock) = create_starting_server(9041, StartupOrderHandling::Queue)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    for order in ["BUY:APPLE@clid=1", "SELL:APPLE@clid=2"] {
+        client.send_line(order).await.expect("Failed to send order");
+    }
+    // Answered right away, the orders are held back
+    client.send_line("PING").await.expect("Failed to send ping");
+    client
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+
+    clock.advance(Duration::from_secs(10));
+    for ack in ["ACK:APPLE@clid=1", "ACK:APPLE@clid=2"] {
+        client
+            .expect_line(ack)
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_trades_are_delayed_for_subscribers_only() {
+    let clock = MockClock::new();
+    let mut handle = create_server(9042).await.expect("Failed to create server");
+    handle.encoder = Encoder::new(EncoderConfig {
+        market_data_delay: Some(Duration::from_millis(500)),
+        clock: Arc::new(clock.clone()),
+        ..EncoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("APPLE")
+        .await
+        .expect("Failed to subscribe");
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    for order in ["BUY:APPLE", "SELL:APPLE"] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    // Without the delay the trade would be ahead of the pong
+    subscriber
+        .send_line("PING")
+        .await
+        .expect("Failed to send ping");
+    subscriber
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+    clock.advance(Duration::from_millis(500));
+    subscriber
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_orders_sent_right_before_closing_are_processed() {
+    let handle = create_server(9043).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("APPLE")
+        .await
+        .expect("Failed to subscribe");
+
+    // Closes without reading the login, the last line is cut short by the close
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .expect("Failed to connect");
+    stream
+        .write_all(b"BUY:APPLE\nSELL:APPLE")
+        .await
+        .expect("Failed to send orders");
+    drop(stream);
+
+    subscriber
+        .expect_line("TRADE:APPLE")
+        .await
+        .expect("Failed to receive trade");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_request_split_into_single_bytes_is_decoded_once() {
+    let handle = create_server(9048).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    for byte in b"BUY:APPLE\n" {
+        client
+            .writer
+            .write_all(&[*byte])
+            .await
+            .expect("Failed to send byte");
+        // Gives the decoder a chance to read every byte on its own
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    client.send_line("PING").await.expect("Failed to send ping");
+
+    // A second ack would be ahead of the pong
+    client
+        .expect_line("ACK:APPLE")
+        .await
+        .expect("Failed to receive ack");
+    client
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_followers_hear_about_followed_trades_only() {
+    let handle = create_server(9049).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut follower = TcpClient::connect(&address).await;
+    follower
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    let mut followed = TcpClient::connect(&address).await;
+    let followed_id = followed.login().await.expect("Failed to log in");
+    let mut other = TcpClient::connect(&address).await;
+    other.verify_login().await.expect("Failed to verify login");
+
+    follower
+        .send_line(&format!("FOLLOW:{followed_id}"))
+        .await
+        .expect("Failed to send follow");
+    follower
+        .expect_line(&format!("ACK:FOLLOW:{followed_id}"))
+        .await
+        .expect("Failed to receive follow ack");
+
+    // The order completing a trade is the one its followers hear about
+    other.send_order("BUY:APPLE").await.expect("Order failed");
+    followed
+        .send_order("SELL:APPLE")
+        .await
+        .expect("Order failed");
+    followed
+        .send_order("BUY:APPLE")
+        .await
+        .expect("Order failed");
+    other.send_order("SELL:APPLE").await.expect("Order failed");
+    follower
+        .expect_line(&format!("FOLLOWED_TRADE:{followed_id}:APPLE"))
+        .await
+        .expect("Failed to receive followed trade");
+    follower
+        .send_line("PING")
+        .await
+        .expect("Failed to send ping");
+    follower
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+
+    // Opting out drops the followers, and refuses new ones
+    followed
+        .send_line("OPTS:nofollow")
+        .await
+        .expect("Failed to send option");
+    followed
+        .send_line("PING")
+        .await
+        .expect("Failed to send ping");
+    followed
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+    follower
+        .send_line(&format!("FOLLOW:{followed_id}"))
+        .await
+        .expect("Failed to send follow");
+    follower
+        .expect_line("NACK:NOT_FOLLOWABLE")
+        .await
+        .expect("Failed to receive nack");
+    other.send_order("BUY:APPLE").await.expect("Order failed");
+    followed
+        .send_order("SELL:APPLE")
+        .await
+        .expect("Order failed");
+    follower
+        .send_line("PING")
+        .await
+        .expect("Failed to send ping");
+    follower
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_order_acks_survive_a_tiny_send_buffer() {
+    let config = ServerConfig {
+        send_buffer_size: Some(1),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9050, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    // Sent without waiting, so the acks pile up in the send buffer
+    let orders: Vec<String> = (0..50)
+        .map(|i| format!("BUY:APPLE@clid={i:0>64}"))
+        .collect();
+    for order in &orders {
+        client.send_line(order).await.expect("Failed to send order");
+    }
+    for order in &orders {
+        let ack = order.replacen("BUY:", "ACK:", 1);
+        client
+            .expect_line(&ack)
+            .await
+            .expect("Failed to receive ack");
+    }
+    assert!(!futures[0].is_finished(), "Encoder task exited");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_line_rate_is_capped_per_client() {
+    let clock = MockClock::new();
+    let mut handle = create_server(9051).await.expect("Failed to create server");
+    handle.decoder = Decoder::new(DecoderConfig {
+        max_line_rate: Some(5),
+        clock: Arc::new(clock.clone()),
+        ..DecoderConfig::default()
+    });
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut flooder = TcpClient::connect(&address).await;
+    flooder
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    let mut other = TcpClient::connect(&address).await;
+    other.verify_login().await.expect("Failed to verify login");
+
+    flooder
+        .writer
+        .write_all("BUY:APPLE\n".repeat(10).as_bytes())
+        .await
+        .expect("Failed to send orders");
+    for _ in 0..5 {
+        flooder
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+    // The flooder being held back does not hold back anyone else
+    other.send_line("PING").await.expect("Failed to send ping");
+    other
+        .expect_line("PONG")
+        .await
+        .expect("Failed to receive pong");
+    let next_line = tokio::time::timeout(Duration::from_millis(100), flooder.read_line()).await;
+    assert!(next_line.is_err(), "Read over the line rate: {next_line:?}");
+
+    clock.advance(Duration::from_secs(1));
+    for _ in 0..5 {
+        flooder
+            .expect_line("ACK:APPLE")
+            .await
+            .expect("Failed to receive ack");
+    }
+
+    // Still over the rate, yet a liveness probe is answered right away and not charged
+    for _ in 0..3 {
+        flooder
+            .send_line("PING")
+            .await
+            .expect("Failed to send ping");
+        tokio::time::timeout(Duration::from_millis(100), flooder.expect_line("PONG"))
+            .await
+            .expect("Pong held back by the line rate")
+            .expect("Failed to receive pong");
+    }
+    flooder
+        .send_line("BUY:APPLE")
+        .await
+        .expect("Failed to send order");
+    let next_line = tokio::time::timeout(Duration::from_millis(100), flooder.read_line()).await;
+    assert!(next_line.is_err(), "Read over the line rate: {next_line:?}");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_nack_only_clients_are_not_acked() {
+    let handle = create_server(9034).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .send_line("OPTS:nackonly")
+        .await
+        .expect("Failed to set option");
+
+    // The duplicate is the only one of the two orders that gets an answer
+    for order in ["BUY:APPLE@clid=1", "BUY:APPLE@clid=1"] {
+        client.send_line(order).await.expect("Failed to send order");
+    }
+    client
+        .expect_line("NACK:DUPLICATE")
+        .await
+        .expect("Failed to receive nack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_echo_mode_returns_orders_without_matching() {
+    let config = ServerConfig {
+        order_handling: OrderHandling::Echo,
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9024, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut trader = TcpClient::connect(&address).await;
+    trader.verify_login().await.expect("Failed to verify login");
+    let mut subscriber = TcpClient::connect(&address).await;
+    subscriber
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+    subscriber
+        .subscribe("APPLE")
+        .await
+        .expect("Failed to subscribe");
+
+    // Would cross when matching
+    for (order, echo) in [
+        ("BUY:APPLE", "ECHO:BUY:APPLE"),
+        ("SELL:APPLE@clid=1", "ECHO:SELL:APPLE"),
+    ] {
+        trader.send_line(order).await.expect("Failed to send order");
+        trader
+            .expect_line(echo)
+            .await
+            .expect("Failed to receive echo");
+    }
+
+    // No trade was queued ahead of the info responses
+    for client in [&mut trader, &mut subscriber] {
+        client.send_line("INFO").await.expect("Failed to send info");
+        let line = client
+            .read_line()
+            .await
+            .expect("Failed to read line")
+            .expect("Expected a line");
+        assert!(line.starts_with("INFO:"), "Expected info, got: {line}");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_login_frame_bytes() {
+    let config = ServerConfig {
+        client_id_allocation: ClientIdAllocation::Sequential,
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9039, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client
+        .assert_bytes(b"LOGIN:1\n")
+        .await
+        .expect("Failed to receive login");
+    // Anything left of the login frame, e.g. a second newline, would come before the pong
+    client.send_line("PING").await.expect("Failed to send ping");
+    client
+        .assert_bytes(b"PONG\n")
+        .await
+        .expect("Failed to receive pong");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_sequential_client_ids() {
+    let config = ServerConfig {
+        client_id_allocation: ClientIdAllocation::Sequential,
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9025, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut first = TcpClient::connect(&address).await;
+    first
+        .expect_line("LOGIN:1")
+        .await
+        .expect("Failed to receive login");
+    let mut second = TcpClient::connect(&address).await;
+    second
+        .expect_line("LOGIN:2")
+        .await
+        .expect("Failed to receive login");
+
+    second.send_line("hello").await.expect("Failed to send");
+    first
+        .expect_line("MESSAGE:2 hello")
+        .await
+        .expect("Failed to receive message");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_rate_limited_connection_gets_retry_after() {
+    let config = ServerConfig {
+        max_accept_rate: Some(1),
+        ..ServerConfig::default()
+    };
+    let handle = create_server_with_config(9019, config)
+        .await
+        .expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut accepted = TcpClient::connect(&address).await;
+    accepted
+        .verify_login()
+        .await
+        .expect("Failed to verify login");
+
+    let mut rejected = TcpClient::connect(&address).await;
+    let line = rejected
+        .read_line()
+        .await
+        .expect("Failed to read line")
+        .expect("Expected a line");
+    regex_matches(&line, r"^NACK:RATE_LIMITED retry_after=\d+$").expect("Missing backoff hint");
+    assert_eq!(
+        rejected.read_line().await.expect("Failed to read line"),
+        None,
+        "Rejected connection was not closed"
+    );
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+/// Connects `clients` clients at once, each subscribing to APPLE and then submitting
+/// `orders_per_client` alternating buys and sells without waiting for acks. Checks that every
+/// client sees its LOGIN first, its acks in submission order, and every trade.
+async fn assert_ordering_under_concurrency(port: u16, clients: usize, orders_per_client: usize) {
+    let handle = create_server(port).await.expect("Failed to create server");
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    // Buys and sells are balanced, so every buy ends up trading against a sell
+    let total_trades = clients * orders_per_client / 2;
+    let subscribed = Arc::new(tokio::sync::Barrier::new(clients));
+    let address = format!("0.0.0.0:{port}");
+
+    let tasks: Vec<_> = (0..clients)
+        .map(|i| {
+            let subscribed = subscribed.clone();
+            let address = address.clone();
+            tokio::spawn(async move {
+                let mut client = TcpClient::connect(&address).await;
+                client.verify_login().await.context("LOGIN was not first")?;
+                client.subscribe("APPLE").await?;
+                // Nobody trades before everyone can see the trades
+                subscribed.wait().await;
+
+                for k in 0..orders_per_client {
+                    let side = if (i + k) % 2 == 0 { "BUY" } else { "SELL" };
+                    client.send_line(&format!("{side}:APPLE@clid={k}")).await?;
+                }
+
+                let (mut acks, mut trades) = (0, 0);
+                while acks < orders_per_client || trades < total_trades {
+                    let line = client.read_line().await?.context("Connection closed")?;
+                    if line == "TRADE:APPLE" {
+                        trades += 1;
+                        continue;
+                    }
+                    anyhow::ensure!(
+                        line == format!("ACK:APPLE@clid={acks}"),
+                        "Client {i} expected ack {acks}, got: {line}"
+                    );
+                    acks += 1;
+                }
+                anyhow::ensure!(trades == total_trades, "Client {i} saw {trades} trades");
+
+                anyhow::Ok(())
+            })
+        })
+        .collect();
+
+    let results = tokio::time::timeout(Duration::from_secs(30), futures::future::join_all(tasks))
+        .await
+        .expect("Clients did not receive every frame in time");
+    for result in results {
+        result
+            .expect("Client task panicked")
+            .expect("Ordering violated");
+    }
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}
+
+#[tokio::test]
+async fn test_ordering_with_50_concurrent_clients() {
+    assert_ordering_under_concurrency(9020, 50, 10).await;
+}
+
+/// Passes on every trade it is handed.
+#[derive(Debug)]
+struct RecordingTradeSink(tokio::sync::mpsc::UnboundedSender<Execution>);
+
+impl TradeSink for RecordingTradeSink {
+    fn publish<'a>(&'a self, trade: &'a Execution) -> BoxFuture<'a, ()> {
+        let _ = self.0.send(*trade);
+        Box::pin(async {})
+    }
+}
+
+#[tokio::test]
+async fn test_trades_are_published_to_the_trade_sink() {
+    let (sender, mut trades) = tokio::sync::mpsc::unbounded_channel();
+    let mut handle = create_server(9052).await.expect("Failed to create server");
+    handle.server = handle
+        .server
+        .with_trade_sink(Arc::new(RecordingTradeSink(sender)));
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    for order in [
+        "BUY:APPLE",
+        "SELL:APPLE",
+        "SELL:PEAR",
+        "BUY:PEAR",
+        "BUY:APPLE",
+    ] {
+        client
+            .send_order(order)
+            .await
+            .expect("Failed to send order");
+    }
+
+    let mut published = Vec::new();
+    for _ in 0..2 {
+        let trade = tokio::time::timeout(Duration::from_secs(1), trades.recv())
+            .await
+            .expect("Timed out waiting for a trade")
+            .expect("Trade sink dropped");
+        published.push(trade);
+    }
+    assert_eq!(
+        published
+            .iter()
+            .map(|trade| (trade.product, trade.side))
+            .collect::<Vec<_>>(),
+        [(Product::Apples, Side::Sell), (Product::Pears, Side::Buy)]
+    );
+    assert!(published[0].trade_id < published[1].trade_id);
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+    assert!(trades.try_recv().is_err(), "Unexpected trade published");
+}
+
+#[tokio::test]
+async fn test_crc_trailers_are_added_and_checked() {
+    let handle = create_server(9053).await.expect("Failed to create server");
+    let address = handle.address.clone();
+    let (futures, cancellation_token) = run_all(handle).await.expect("Failed to run server");
+
+    let mut client = TcpClient::connect(&address).await;
+    client.verify_login().await.expect("Failed to verify login");
+    client
+        .send_line("OPTS:crc")
+        .await
+        .expect("Failed to send option");
+
+    let with_crc = |line: &str| {
+        let mut framed = Vec::new();
+        codec::append_with_crc(line.as_bytes(), &mut framed);
+        String::from_utf8(framed).expect("Invalid UTF-8")
+    };
+    client
+        .send_line(&with_crc("BUY:APPLE"))
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line(&with_crc("ACK:APPLE"))
+        .await
+        .expect("Failed to receive ack");
+
+    // Corrupted on the way, the trailer no longer matches
+    let corrupted = with_crc("BUY:APPLE").replace("APPLE|", "PEAR|");
+    client
+        .send_line(&corrupted)
+        .await
+        .expect("Failed to send order");
+    client
+        .expect_line(&with_crc("NACK:BAD_CRC"))
+        .await
+        .expect("Failed to receive nack");
+
+    stop_all(futures, cancellation_token)
+        .await
+        .expect("Failed to stop server");
+}